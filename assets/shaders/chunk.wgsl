@@ -0,0 +1,58 @@
+// Fragment shader of the chunk meshes. Merged faces span several blocks, so the texture
+// coordinates count blocks and are wrapped into the block's atlas tile here.
+
+#import bevy_pbr::{
+    pbr_bindings,
+    pbr_fragment::pbr_input_from_standard_material,
+    pbr_functions::alpha_discard,
+}
+
+#ifdef PREPASS_PIPELINE
+#import bevy_pbr::{
+    prepass_io::{VertexOutput, FragmentOutput},
+    pbr_deferred_functions::deferred_output,
+}
+#else
+#import bevy_pbr::{
+    forward_io::{VertexOutput, FragmentOutput},
+    pbr_functions::{apply_pbr_lighting, main_pass_post_lighting_processing},
+}
+#endif
+
+// The atlas is a grid of 4x4 tiles
+const ATLAS_TILE_SIZE: f32 = 0.25;
+
+@fragment
+fn fragment(
+    in: VertexOutput,
+    @builtin(front_facing) is_front: bool,
+) -> FragmentOutput {
+    var tiled = in;
+#ifdef VERTEX_UVS_B
+    // uv counts blocks across the face, uv_b is the corner of the block's tile
+    tiled.uv = in.uv_b + fract(in.uv) * ATLAS_TILE_SIZE;
+#endif
+
+    var pbr_input = pbr_input_from_standard_material(tiled, is_front);
+#ifdef VERTEX_UVS_B
+    // fract jumps at every block seam, which would make the GPU pick the smallest mip there and bleed
+    // the neighbouring tiles in, so the atlas is sampled again with the gradients of the unwrapped uv
+    pbr_input.material.base_color = pbr_bindings::material.base_color * textureSampleGrad(
+        pbr_bindings::base_color_texture,
+        pbr_bindings::base_color_sampler,
+        tiled.uv,
+        dpdx(in.uv) * ATLAS_TILE_SIZE,
+        dpdy(in.uv) * ATLAS_TILE_SIZE,
+    );
+#endif
+    pbr_input.material.base_color = alpha_discard(pbr_input.material, pbr_input.material.base_color);
+
+#ifdef PREPASS_PIPELINE
+    let out = deferred_output(tiled, pbr_input);
+#else
+    var out: FragmentOutput;
+    out.color = apply_pbr_lighting(pbr_input);
+    out.color = main_pass_post_lighting_processing(pbr_input, out.color);
+#endif
+    return out;
+}
@@ -38,7 +38,9 @@ while True:
 
         if stop_attack:
             client.rotate(0.0, 0.2)
-            client.use_item()
+            # The bow shoots when it stops being used, so let go once it's fully drawn
+            if client.state.players[client.state.player_id].bow_charge < 1.0:
+                client.use_item()
 
     def on_health_change(player_id, old_health, new_health):
         global stop_attack
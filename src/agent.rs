@@ -0,0 +1,321 @@
+use crate::client::GameConnection;
+use crate::config::MatchConfig;
+use crate::player::{Inventory, Item, PLAYER_HEIGHT, PlayerActions, PlayerID, Rotation};
+use crate::sim::raycast_for_block;
+use crate::world::{BlockType, ChunkMap};
+use crate::{GameResults, PlayerInfo, TickMessage, WorldSnapshot};
+use anyhow::bail;
+use bevy::math::{IVec3, Vec3};
+use std::sync::mpmc::TryRecvError;
+use std::time::Duration;
+use workflow_websocket::client::Message;
+
+mod bots;
+
+pub use bots::*;
+
+/// An agent written in Rust, playing one side of a match
+pub trait Agent {
+    /// Called for every tick after `view` has been updated with it, returns the actions to answer it with
+    fn act(&mut self, tick: &TickMessage, view: &GameView) -> PlayerActions;
+}
+
+impl<F: FnMut(&TickMessage, &GameView) -> PlayerActions> Agent for F {
+    fn act(&mut self, tick: &TickMessage, view: &GameView) -> PlayerActions {
+        self(tick, view)
+    }
+}
+
+/// The state of a match as seen by a player, built up from the starting snapshot and the ticks since they only contain changes
+pub struct GameView {
+    pub player_id: PlayerID,
+    pub tick: u64,
+    pub players: [PlayerInfo; 2],
+    pub health: [f32; 2],
+    pub inventories: [Inventory; 2],
+    pub scores: [u16; 2],
+    pub map: ChunkMap,
+    /// The rules of the match, its map has the spawns, goals and limits of the arena
+    pub config: MatchConfig,
+}
+
+impl GameView {
+    /// The view of `player_id` on the world in the snapshot, played with the given rules
+    pub fn new(player_id: PlayerID, config: MatchConfig, snapshot: &WorldSnapshot) -> Self {
+        GameView {
+            player_id,
+            tick: snapshot.tick,
+            players: snapshot.players.clone(),
+            health: snapshot.health,
+            inventories: snapshot.inventories.clone(),
+            scores: snapshot.scores,
+            map: snapshot.map.clone(),
+            config,
+        }
+    }
+
+    pub fn update(&mut self, tick: &TickMessage) {
+        self.tick = tick.tick;
+        for (i, info) in tick.players.iter().enumerate() {
+            if let Some(health) = info.health_update {
+                self.health[i] = health;
+            }
+            if let Some(inventory) = &info.inventory_update {
+                self.inventories[i] = inventory.clone();
+            }
+            self.players[i] = info.clone();
+        }
+        if let Some(scorer) = tick.goals {
+            self.scores[scorer.0 as usize] += 1;
+        }
+        self.map.apply_diff(&tick.block_updates);
+    }
+
+    pub fn me(&self) -> &PlayerInfo {
+        &self.players[self.player_id.0 as usize]
+    }
+
+    pub fn opponent(&self) -> &PlayerInfo {
+        &self.players[self.player_id.0 as usize ^ 1]
+    }
+
+    pub fn my_inventory(&self) -> &Inventory {
+        &self.inventories[self.player_id.0 as usize]
+    }
+
+    pub fn my_health(&self) -> f32 {
+        self.health[self.player_id.0 as usize]
+    }
+
+    /// The center of the goal this player scores in
+    pub fn target_goal(&self) -> Vec3 {
+        self.config.map.goal(self.player_id).center()
+    }
+
+    /// The center of the goal the opponent scores in
+    pub fn own_goal(&self) -> Vec3 {
+        self.config.map.goal(PlayerID(self.player_id.0 ^ 1)).center()
+    }
+
+    pub fn spawn(&self) -> Vec3 {
+        self.config.map.spawn(self.player_id).translation()
+    }
+
+    /// The direction along the x axis that leads to the opponent's side
+    pub fn forward(&self) -> f32 {
+        (self.target_goal().x - self.own_goal().x).signum()
+    }
+
+    /// The x coordinate halfway between the goals
+    fn middle_x(&self) -> f32 {
+        (self.target_goal().x + self.own_goal().x) / 2.0
+    }
+
+    /// How far a position is along the way to the opponent's side, measured from halfway between the goals.
+    /// The bridge spans from `-bridge_reach()` to `bridge_reach()`.
+    pub fn progress(&self, pos: Vec3) -> f32 {
+        (pos.x - self.middle_x()) * self.forward()
+    }
+
+    /// How far the bridge reaches from halfway between the goals, as measured by [`GameView::progress`].
+    /// The spawns are taken to be on the first block of the islands, right after the bridge.
+    pub fn bridge_reach(&self) -> f32 {
+        self.progress(self.spawn()).abs() - 0.5
+    }
+
+    /// The z coordinate of the lane across the bridge, which runs through the spawns
+    pub fn bridge_z(&self) -> f32 {
+        self.spawn().z
+    }
+
+    /// The y coordinate of the blocks the players spawn on, which is the top of the bridge
+    pub fn ground_y(&self) -> i32 {
+        self.config.map.spawn(self.player_id).position.y.floor() as i32 - 1
+    }
+
+    /// The first z coordinate beside the bridge on its +z side, looking at the blocks halfway between the goals
+    pub fn beside_bridge_z(&self) -> i32 {
+        let mut pos = IVec3::new(
+            self.middle_x().floor() as i32,
+            self.ground_y(),
+            self.bridge_z().floor() as i32,
+        );
+        while self.map.get_block(pos) != BlockType::Air
+            && self.config.map.build_limits.contains(pos)
+        {
+            pos.z += 1;
+        }
+        pos.z
+    }
+
+    pub fn eye_position(&self) -> Vec3 {
+        self.me().stance.eye_position(self.me().position)
+    }
+
+    /// The yaw (in this player's frame) that faces the target
+    pub fn yaw_towards(&self, target: Vec3) -> f32 {
+        let delta = target - self.me().position;
+        let world_yaw = (-delta.z).atan2(delta.x);
+        if self.player_id.0 == 1 {
+            world_yaw
+        } else {
+            world_yaw - std::f32::consts::PI
+        }
+    }
+
+    /// The pitch that looks from this player's eyes at the target
+    pub fn pitch_towards(&self, target: Vec3) -> f32 {
+        let delta = target - self.eye_position();
+        delta.y.atan2(delta.with_y(0.0).length())
+    }
+
+    /// The block this player would place against or dig when looking in the given direction, and the face that would be hit
+    pub fn targeted_block(&self, rotation: Rotation) -> Option<(IVec3, IVec3)> {
+        raycast_for_block(
+            self.eye_position(),
+            rotation.to_quat(self.player_id),
+            &self.map,
+        )
+    }
+
+    fn on_bridge(&self) -> bool {
+        self.progress(self.me().position).abs() < self.bridge_reach()
+    }
+
+    /// Where to walk next on the way to the target. Crossing the gap between the islands is done on the
+    /// lane of the bridge through the spawns.
+    pub fn waypoint(&self, target: Vec3) -> Vec3 {
+        let me = self.me().position;
+        let middle = self.middle_x();
+        let same_side = (me.x - middle).signum() == (target.x - middle).signum();
+        if self.on_bridge() {
+            target.with_z(self.bridge_z())
+        } else if same_side {
+            target
+        } else {
+            // Head for the end of the bridge on this island
+            let side = (me.x - middle).signum();
+            Vec3::new(
+                middle + side * (self.bridge_reach() - 0.5),
+                me.y,
+                self.bridge_z(),
+            )
+        }
+    }
+
+    /// Actions that walk towards the target, looking straight ahead.
+    /// Gaps in the bridge are built across while sneaking, if the player has blocks left.
+    pub fn walk_towards(&self, target: Vec3) -> PlayerActions {
+        let me = self.me().position;
+        let waypoint = self.waypoint(target);
+        let direction = (waypoint.x - me.x).signum();
+        let below = (me.y - PLAYER_HEIGHT / 2.0).floor() as i32 - 1;
+        let ahead = IVec3::new(
+            (me.x + direction * 0.6).floor() as i32,
+            below,
+            me.z.floor() as i32,
+        );
+        // Only while standing on the bridge, jumps and falls aren't gaps
+        if self.on_bridge()
+            && below == self.ground_y()
+            && self.map.get_block(ahead) == BlockType::Air
+            && self.my_inventory().get_count(Item::Block) > 0
+        {
+            return self.build_towards(direction, ahead.z);
+        }
+
+        let mut actions = PlayerActions::default();
+        actions.rotation = Rotation {
+            yaw: self.yaw_towards(waypoint),
+            pitch: 0.0,
+        };
+        actions.set(PlayerActions::MOVE_FORWARD);
+        actions
+    }
+
+    /// Actions that sneak along the blocks at `lane_z` in `direction` along the x axis (1 or -1),
+    /// placing a block against the end of them whenever there is none ahead
+    pub fn build_towards(&self, direction: f32, lane_z: i32) -> PlayerActions {
+        let me = self.me().position;
+        let mut actions = PlayerActions::default();
+        actions.rotation.yaw = self.yaw_towards(Vec3::new(
+            me.x + direction * 10.0,
+            me.y,
+            lane_z as f32 + 0.5,
+        ));
+        // Sneaking keeps the player from walking off the end of the blocks
+        actions.set(PlayerActions::SNEAK);
+        let feet = me.y - PLAYER_HEIGHT / 2.0;
+        let ahead = IVec3::new(
+            (me.x + direction * 0.6).floor() as i32,
+            feet.floor() as i32 - 1,
+            lane_z,
+        );
+        if self.map.get_block(ahead) != BlockType::Air {
+            actions.set(PlayerActions::MOVE_FORWARD);
+            return actions;
+        }
+
+        // Stop at the edge and place against the side of the last block
+        let edge_x = if direction > 0.0 {
+            ahead.x as f32
+        } else {
+            ahead.x as f32 + 1.0
+        };
+        let target = Vec3::new(edge_x, ahead.y as f32 + 0.5, lane_z as f32 + 0.5);
+        actions.rotation.pitch = self.pitch_towards(target);
+        if self.my_inventory().get_selected_item() != Item::Block {
+            actions.item_change = Some(Item::Block);
+        }
+        if let Some((block, face)) = self.targeted_block(actions.rotation)
+            && block + face == ahead
+        {
+            actions.checked_set(PlayerActions::PLACE_BLOCK);
+        }
+        actions
+    }
+}
+
+/// Plays a match with the agent until it ends. Returns the results, or `None` if the server closed the connection.
+pub async fn run_agent(
+    connection: &GameConnection,
+    agent: &mut dyn Agent,
+) -> anyhow::Result<Option<GameResults>> {
+    if connection.spectator {
+        bail!("Spectators can't play");
+    }
+    let mut view = GameView::new(
+        connection.player_id,
+        connection.config.clone(),
+        &connection.snapshot,
+    );
+
+    loop {
+        // The channel blocks, so it is polled to leave the executor free to drive the websocket
+        let msg = match connection.receiver_rx.try_recv() {
+            Ok(msg) => msg,
+            Err(TryRecvError::Empty) => {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let Message::Binary(data) = msg else {
+            if msg == Message::Close {
+                return Ok(None);
+            }
+            bail!("Received invalid message: {:?}", msg);
+        };
+        let tick: TickMessage = postcard::from_bytes(&data)?;
+        if let Some(results) = tick.game_results {
+            return Ok(Some(results));
+        }
+
+        view.update(&tick);
+        let actions = agent.act(&tick, &view);
+        connection
+            .socket
+            .send(Message::Binary(postcard::to_allocvec(&actions)?))
+            .await?;
+    }
+}
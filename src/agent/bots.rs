@@ -0,0 +1,171 @@
+//! Reference bots to play against or to build on
+
+use crate::agent::{Agent, GameView};
+use crate::player::{BowCharge, Item, PlayerActions, Rotation};
+use crate::{ARROW_GRAVITY_SCALE, TickMessage};
+use avian3d::prelude::Gravity;
+use bevy::math::Vec3;
+
+/// Distance under which the bots go after the opponent instead of their objective
+const CHASE_RANGE: f32 = 8.0;
+/// Distance under which the bots swing at the opponent, a bit less than the reach of attacks
+const ATTACK_RANGE: f32 = 2.8;
+
+/// Swings the sword at the opponent while running at them, waiting for full charge between swings
+fn melee(view: &GameView, actions: &mut PlayerActions) {
+    let opponent = view.opponent().position;
+    if view.my_inventory().get_selected_item() != Item::Sword {
+        actions.item_change = Some(Item::Sword);
+    }
+    actions.rotation.pitch = view.pitch_towards(opponent);
+    if view.me().attack_charge >= 1.0 {
+        actions.checked_set(PlayerActions::ATTACK);
+    }
+}
+
+/// Runs straight for the goal, fighting the opponent when they get in the way
+pub struct Rusher;
+
+impl Agent for Rusher {
+    fn act(&mut self, _tick: &TickMessage, view: &GameView) -> PlayerActions {
+        let opponent = view.opponent().position;
+        let distance = view.me().position.distance(opponent);
+        let target = if distance < CHASE_RANGE {
+            opponent
+        } else {
+            view.target_goal()
+        };
+
+        let mut actions = view.walk_towards(target);
+        // Sneaking means it is building across a gap
+        if !actions.is_set(PlayerActions::SNEAK) {
+            actions.set(PlayerActions::JUMP);
+            actions.set(PlayerActions::SPRINT);
+        }
+        if distance < ATTACK_RANGE {
+            melee(view, &mut actions);
+        }
+        actions
+    }
+}
+
+/// Builds its own bridge next to the one in the middle of the arena to get around the opponent
+pub struct Bridger {
+    /// Blocks of air between the bridge of the arena and the one it builds
+    pub gap: i32,
+}
+
+impl Default for Bridger {
+    fn default() -> Self {
+        Bridger { gap: 1 }
+    }
+}
+
+impl Agent for Bridger {
+    fn act(&mut self, tick: &TickMessage, view: &GameView) -> PlayerActions {
+        let me = view.me().position;
+        let lane = view.beside_bridge_z() + self.gap;
+        let lane_z = lane as f32 + 0.5;
+
+        // Across, or nothing left to build with
+        if view.progress(me) > view.bridge_reach()
+            || view.my_inventory().get_count(Item::Block) == 0
+        {
+            return Rusher.act(tick, view);
+        }
+
+        if view.progress(me) < -view.bridge_reach() && (me.z - lane_z).abs() > 0.25 {
+            let mut actions = PlayerActions::default();
+            // Line up with the lane on the home island first
+            let start = view.spawn().with_z(lane_z);
+            actions.rotation.yaw = view.yaw_towards(start);
+            actions.set(PlayerActions::MOVE_FORWARD);
+            return actions;
+        }
+
+        view.build_towards(view.forward(), lane)
+    }
+}
+
+/// Distance the archer walks up to before drawing the bow
+const BOW_RANGE: f32 = 20.0;
+/// Distance under which the archer puts the bow away and rushes the opponent
+const ARCHER_MELEE_RANGE: f32 = 5.0;
+
+/// Shoots at the opponent from a distance and only fights up close
+pub struct Archer;
+
+impl Agent for Archer {
+    fn act(&mut self, tick: &TickMessage, view: &GameView) -> PlayerActions {
+        let opponent = view.opponent().position;
+        let distance = view.me().position.distance(opponent);
+        if distance < ARCHER_MELEE_RANGE || view.my_inventory().get_count(Item::Arrow) == 0 {
+            return Rusher.act(tick, view);
+        }
+        if distance > BOW_RANGE {
+            return view.walk_towards(opponent);
+        }
+
+        // Aim above the opponent to make up for the arrow dropping on its way. The bow is only let go
+        // once it is fully drawn, so the arrow flies at the speed of a full draw.
+        let full_draw = BowCharge(view.config.bow_charge_ticks);
+        let arrow_speed = view.config.arrow_speed * full_draw.power(view.config.bow_charge_ticks);
+        let gravity = Gravity::default().0.length() * ARROW_GRAVITY_SCALE;
+        let flight_time = distance / arrow_speed.max(1.0);
+        let drop = 0.5 * gravity * flight_time * flight_time;
+        let mut actions = PlayerActions::default();
+        actions.rotation = Rotation {
+            yaw: view.yaw_towards(opponent),
+            pitch: view.pitch_towards(opponent + Vec3::Y * drop),
+        };
+        if view.my_inventory().get_selected_item() != Item::Bow {
+            actions.item_change = Some(Item::Bow);
+        }
+        // Letting go of the fully drawn bow shoots the arrow
+        if view.me().bow_charge < 1.0 {
+            actions.checked_set(PlayerActions::USE_ITEM);
+        }
+        actions
+    }
+}
+
+/// Health under which the defender eats a golden apple
+const DEFENDER_HEAL_THRESHOLD: f32 = 10.0;
+
+/// Stays in front of its own goal and fights off the opponent
+pub struct Defender;
+
+impl Agent for Defender {
+    fn act(&mut self, _tick: &TickMessage, view: &GameView) -> PlayerActions {
+        let me = view.me().position;
+        let opponent = view.opponent().position;
+        let distance = me.distance(opponent);
+        let own_goal = view.own_goal();
+        let guard = Vec3::new(
+            own_goal.x + view.forward() * 4.0,
+            view.spawn().y,
+            own_goal.z,
+        );
+
+        if distance < ATTACK_RANGE + 0.5 {
+            let mut actions = view.walk_towards(opponent);
+            melee(view, &mut actions);
+            return actions;
+        }
+
+        let mut actions = PlayerActions::default();
+        if view.my_health() < DEFENDER_HEAL_THRESHOLD
+            && distance > CHASE_RANGE - 2.0
+            && view.my_inventory().get_count(Item::GoldenApple) > 0
+        {
+            if view.my_inventory().get_selected_item() != Item::GoldenApple {
+                actions.item_change = Some(Item::GoldenApple);
+            }
+            actions.checked_set(PlayerActions::USE_ITEM);
+        } else if me.with_y(0.0).distance(guard.with_y(0.0)) > 0.5 {
+            return view.walk_towards(guard);
+        }
+        actions.rotation.yaw = view.yaw_towards(opponent);
+        actions
+    }
+}
@@ -1,23 +1,35 @@
 use agentduels::states::GamePlugin;
 use agentduels::states::network::OpponentDisconnected;
-use agentduels::{
-    ControlServer, SERVER_URL, client::GameConnection, handle_connection, handle_disconnects,
-};
+use agentduels::client::{ClientInfo, ConnectionArgs};
+use agentduels::{ControlServer, client::GameConnection, handle_connection, handle_disconnects};
 use bevy::DefaultPlugins;
 use bevy::app::App;
 use bevy::prelude::*;
 use bevy::render::RenderPlugin;
 use bevy::render::settings::{RenderCreation, WgpuSettings};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use clap::Parser;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 const CONTROL_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8083);
 
+#[derive(Parser)]
+#[command(about = "AgentDuels headless client")]
+struct Args {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+}
+
 #[tokio::main]
 async fn main() {
-    let listener = TcpListener::bind(CONTROL_ADDR).unwrap();
-    listener.set_nonblocking(true).unwrap();
+    let args = Args::parse();
+    let control_server = ControlServer::bind(args.connection.control_addr.unwrap_or(CONTROL_ADDR))
+        .unwrap()
+        .with_format(args.connection.control_format);
 
-    let connection = GameConnection::connect(SERVER_URL).await.unwrap();
+    let info = ClientInfo::new("agentduels-headless", args.connection.agent_name.clone());
+    let connection = GameConnection::connect(&args.connection.server_url, info)
+        .await
+        .unwrap();
 
     App::new()
         .add_plugins(DefaultPlugins.set(RenderPlugin {
@@ -29,7 +41,7 @@ async fn main() {
             ..default()
         }))
         .insert_resource(connection)
-        .insert_resource(ControlServer::new(listener))
+        .insert_resource(control_server)
         .add_systems(FixedUpdate, (handle_connection, handle_disconnects))
         .add_plugins(GamePlugin::new(true))
         .add_observer(handle_opponent_disconnect)
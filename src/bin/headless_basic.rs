@@ -1,36 +1,64 @@
 #![feature(mpmc_channel)]
 
+use agentduels::agent::{Agent, Archer, Bridger, Defender, GameView, Rusher, run_agent};
+use agentduels::client::{ClientInfo, GameConnection};
 use agentduels::player::PlayerActions;
-use agentduels::{SERVER_URL, TickMessage, client::GameConnection};
-use anyhow::bail;
-use workflow_websocket::client::Message;
+use agentduels::{SERVER_URL, TickMessage};
+use clap::{Parser, ValueEnum};
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let connection = GameConnection::connect(SERVER_URL).await?;
+#[derive(Parser)]
+#[command(about = "Headless client that plays without an agent")]
+struct Args {
+    /// Websocket URL of the game server
+    #[arg(long, env = "AGENTDUELS_SERVER_URL", default_value = SERVER_URL)]
+    server_url: String,
+    /// Built-in bot to play with
+    #[arg(long, value_enum, default_value_t = Bot::Basic)]
+    bot: Bot,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum Bot {
+    /// Runs forward, jumping and attacking
+    Basic,
+    Rusher,
+    Bridger,
+    Archer,
+    Defender,
+}
 
-    loop {
-        let msg = connection.receiver_rx.recv()?;
-        let Message::Binary(data) = msg else {
-            if msg == Message::Close {
-                println!("Connection closed by server");
-                return Ok(());
-            }
-            bail!("Received invalid message: {:?}", msg);
-        };
-        let tick_msg: TickMessage = postcard::from_bytes(&data)?;
-        println!("Received tick {}", tick_msg.tick);
+fn basic(tick: &TickMessage, _view: &GameView) -> PlayerActions {
+    println!("Received tick {}", tick.tick);
 
-        let mut actions = PlayerActions::default();
-        actions.set(PlayerActions::MOVE_FORWARD);
-        actions.set(PlayerActions::JUMP);
-        actions.set(PlayerActions::ATTACK);
+    let mut actions = PlayerActions::default();
+    actions.set(PlayerActions::MOVE_FORWARD);
+    actions.set(PlayerActions::JUMP);
+    actions.set(PlayerActions::ATTACK);
+    actions
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let mut agent: Box<dyn Agent> = match args.bot {
+        Bot::Basic => Box::new(basic),
+        Bot::Rusher => Box::new(Rusher),
+        Bot::Bridger => Box::new(Bridger::default()),
+        Bot::Archer => Box::new(Archer),
+        Bot::Defender => Box::new(Defender),
+    };
 
-        connection
-            .socket
-            .send(Message::Binary(
-                postcard::to_allocvec(&actions).unwrap(),
-            ))
-            .await?;
+    let info = ClientInfo::new(
+        "agentduels-headless-basic",
+        Some(format!("{:?} bot", args.bot)),
+    );
+    let connection = GameConnection::connect(&args.server_url, info).await?;
+    match run_agent(&connection, agent.as_mut()).await? {
+        Some(results) => match results.winner {
+            Some(winner) => println!("Player {} won ({})", winner, results.reason),
+            None => println!("Match ended without a winner ({})", results.reason),
+        },
+        None => println!("Connection closed by server"),
     }
+    Ok(())
 }
@@ -0,0 +1,135 @@
+use agentduels::SERVER_ADDR;
+use agentduels::config::MatchConfig;
+use agentduels::map::MapDef;
+use anyhow::Context;
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Command-line arguments of the match server. Flags override values from the config file.
+#[derive(Parser, Debug)]
+#[command(about = "AgentDuels match server")]
+pub struct Args {
+    /// Path to a TOML or JSON (by extension) config file
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    /// Address to listen for websocket connections on
+    #[arg(long)]
+    pub bind: Option<SocketAddr>,
+    /// Seconds a new connection has to complete the handshake (0 waits forever)
+    #[arg(long)]
+    pub handshake_timeout_secs: Option<u64>,
+    /// Directory to record a replay of every match to
+    #[arg(long)]
+    pub replay_dir: Option<PathBuf>,
+    /// Goals needed to win a match
+    #[arg(long)]
+    pub score_to_win: Option<u16>,
+    /// Health players spawn with
+    #[arg(long)]
+    pub max_health: Option<f32>,
+    /// Ticks a player is invulnerable for after taking damage
+    #[arg(long)]
+    pub hurt_cooldown_ticks: Option<u8>,
+    /// Seed every match with this value instead of a random one, to reproduce a recorded match
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Map file (TOML or JSON by extension) to play every match on
+    #[arg(long)]
+    pub map: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub bind_addr: SocketAddr,
+    /// Seconds a new connection has to complete the handshake (0 waits forever)
+    pub handshake_timeout_secs: u64,
+    /// Directory to record a replay of every match to, no replays are recorded if unset
+    pub replay_dir: Option<PathBuf>,
+    /// Map file to play every match on, replacing the map in the match config.
+    /// Relative paths are resolved from the working directory.
+    pub map_file: Option<PathBuf>,
+    #[serde(rename = "match")]
+    pub match_config: MatchConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_addr: SERVER_ADDR,
+            handshake_timeout_secs: 10,
+            replay_dir: None,
+            map_file: None,
+            match_config: MatchConfig::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Loads the config file given in the arguments (if any) and applies the command-line overrides on top
+    pub fn load(args: &Args) -> anyhow::Result<Self> {
+        let mut config = match &args.config {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read config file {}", path.display()))?;
+                if path.extension().is_some_and(|ext| ext == "json") {
+                    serde_json::from_str(&contents)
+                        .with_context(|| format!("Invalid config file {}", path.display()))?
+                } else {
+                    toml::from_str(&contents)
+                        .with_context(|| format!("Invalid config file {}", path.display()))?
+                }
+            }
+            None => ServerConfig::default(),
+        };
+
+        if let Some(bind) = args.bind {
+            config.bind_addr = bind;
+        }
+        if let Some(secs) = args.handshake_timeout_secs {
+            config.handshake_timeout_secs = secs;
+        }
+        if let Some(replay_dir) = &args.replay_dir {
+            config.replay_dir = Some(replay_dir.clone());
+        }
+        if let Some(score_to_win) = args.score_to_win {
+            config.match_config.score_to_win = score_to_win;
+        }
+        if let Some(max_health) = args.max_health {
+            config.match_config.max_health = max_health;
+        }
+        if let Some(ticks) = args.hurt_cooldown_ticks {
+            config.match_config.hurt_cooldown_ticks = ticks;
+        }
+        if let Some(seed) = args.seed {
+            config.match_config.seed = Some(seed);
+        }
+        if let Some(map) = &args.map {
+            config.map_file = Some(map.clone());
+        }
+        match &config.map_file {
+            Some(path) => config.match_config.map = MapDef::load(path)?,
+            // Maps written into the config file are checked the same way as map files
+            None => config
+                .match_config
+                .map
+                .validate()
+                .context("Invalid map in the config")?,
+        }
+        if let Some(generator) = &config.match_config.generator {
+            generator
+                .generate(config.match_config.seed.unwrap_or_default())
+                .context("Invalid map generator in the config")?;
+        }
+
+        Ok(config)
+    }
+
+    pub fn handshake_timeout(&self) -> Option<Duration> {
+        (self.handshake_timeout_secs > 0).then(|| Duration::from_secs(self.handshake_timeout_secs))
+    }
+}
@@ -0,0 +1,93 @@
+use agentduels::player::PlayerActions;
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::time::Instant;
+use tungstenite::{Message, WebSocket};
+
+/// A player's websocket during a match, along with the bookkeeping for the per-tick action deadline
+pub struct PlayerConnection {
+    ws: WebSocket<TcpStream>,
+    connected: bool,
+    /// Clients answer every tick with exactly one actions message, so these counts tell late answers apart
+    ticks_sent: u64,
+    answers_received: u64,
+    pub last_actions: PlayerActions,
+    pub missed_ticks: u32,
+}
+
+impl PlayerConnection {
+    pub fn new(ws: WebSocket<TcpStream>) -> Self {
+        PlayerConnection {
+            ws,
+            connected: true,
+            ticks_sent: 0,
+            answers_received: 0,
+            last_actions: PlayerActions::default(),
+            missed_ticks: 0,
+        }
+    }
+
+    /// Sends the snapshot of the world the client starts from, before the first tick
+    pub fn send_snapshot(&mut self, msg: &[u8]) {
+        if self.ws.send(Message::binary(msg.to_vec())).is_err() {
+            self.connected = false;
+        }
+    }
+
+    pub fn send_tick(&mut self, msg: &[u8]) {
+        if !self.connected {
+            return;
+        }
+        if self.ws.send(Message::binary(msg.to_vec())).is_err() {
+            self.connected = false;
+        }
+        self.ticks_sent += 1;
+    }
+
+    /// Waits until the deadline for the actions answering the last tick sent.
+    /// Returns `Ok(None)` if the deadline passed. Answers to earlier ticks that arrive late are discarded.
+    pub fn recv_actions(
+        &mut self,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<Option<PlayerActions>> {
+        if !self.connected {
+            anyhow::bail!("Connection closed");
+        }
+        loop {
+            if let Some(deadline) = deadline {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Ok(None);
+                }
+                self.ws.get_ref().set_read_timeout(Some(deadline - now))?;
+            }
+            match self.ws.read() {
+                Ok(msg) if msg.is_binary() => {
+                    self.answers_received += 1;
+                    if self.answers_received < self.ticks_sent {
+                        continue;
+                    }
+                    return Ok(Some(postcard::from_bytes(&msg.into_data())?));
+                }
+                // Ignore pings and other non-binary messages
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(e))
+                    if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+                {
+                    return Ok(None);
+                }
+                Err(e) => {
+                    self.connected = false;
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+
+    pub fn close(&mut self) {
+        if self.connected {
+            let _ = self.ws.close(None);
+            let _ = self.ws.flush();
+        }
+    }
+}
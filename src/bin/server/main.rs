@@ -1,25 +1,60 @@
+use crate::config::{Args, ServerConfig};
+use crate::spectators::Spectators;
+use agentduels::GAME_VERSION;
+use agentduels::config::MatchConfig;
+use agentduels::handshake::{ClientHello, ClientMode, Encoding, ServerHello};
 use agentduels::player::PlayerID;
-use agentduels::{GAME_VERSION, SERVER_ADDR};
+use agentduels::replay::{ReplayHeader, ReplayPlayer, ReplayWriter};
 use anyhow::bail;
+use clap::Parser;
+use std::fs;
 use std::net::{TcpListener, TcpStream};
 use std::sync::{Arc, Mutex, PoisonError};
 use std::thread::spawn;
 use tungstenite::{Message, WebSocket, accept};
 
 mod app;
+mod config;
+mod connection;
+mod spectators;
 
-fn main() {
-    let server = TcpListener::bind(SERVER_ADDR).unwrap();
+/// Encodings of the tick stream the server can send
+const SUPPORTED_ENCODINGS: [Encoding; 1] = [Encoding::Postcard];
+
+fn main() -> anyhow::Result<()> {
+    let config = Arc::new(ServerConfig::load(&Args::parse())?);
+    let server = TcpListener::bind(config.bind_addr)?;
+    println!("Listening on {}", config.bind_addr);
     let queue: Arc<Mutex<Option<WebSocket<TcpStream>>>> = Arc::new(Mutex::new(None));
+    let spectators = Spectators::default();
 
     for stream in server.incoming() {
         let queue = queue.clone();
+        let spectators = spectators.clone();
+        let config = config.clone();
         spawn(move || {
             if let Ok(stream) = stream {
-                let _ = handle_connection(stream, queue).unwrap();
+                if let Err(e) = handle_connection(stream, queue, &spectators, &config) {
+                    eprintln!("Connection error: {e:#}");
+                }
             }
         });
     }
+
+    Ok(())
+}
+
+/// Sends a handshake answer to the client
+fn send_hello(ws: &mut WebSocket<TcpStream>, hello: &ServerHello) -> anyhow::Result<()> {
+    ws.send(Message::binary(postcard::to_allocvec(hello)?))?;
+    Ok(())
+}
+
+/// Tells the client why it can't connect and closes the connection
+fn reject(ws: &mut WebSocket<TcpStream>, reason: String) {
+    let _ = send_hello(ws, &ServerHello::Reject { reason });
+    let _ = ws.close(None);
+    let _ = ws.flush();
 }
 
 /// Reads messages from the WebSocket until a binary message is received. (Used to ignore pings.)
@@ -35,36 +70,122 @@ fn read_until_binary(ws: &mut WebSocket<TcpStream>) -> anyhow::Result<Vec<u8>> {
 fn handle_connection(
     stream: TcpStream,
     queue: Arc<Mutex<Option<WebSocket<TcpStream>>>>,
+    spectators: &Spectators,
+    config: &ServerConfig,
 ) -> anyhow::Result<()> {
+    stream.set_read_timeout(config.handshake_timeout())?;
     let mut websocket = accept(stream)?;
 
-    if let Ok(data) = read_until_binary(&mut websocket) {
-        if data.len() != 4 {
-            bail!("Wrong data length");
-        }
-        let client_version = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
-        if client_version != GAME_VERSION {
-            bail!("Version mismatch");
+    let hello = match read_until_binary(&mut websocket)
+        .and_then(|data| Ok(postcard::from_bytes::<ClientHello>(&data)?))
+    {
+        Ok(hello) => hello,
+        Err(e) => {
+            reject(
+                &mut websocket,
+                "Invalid hello message, the client may be outdated".to_string(),
+            );
+            return Err(e.context("Invalid hello message"));
         }
-    } else {
-        bail!("Unexpected message");
     };
+    if hello.version != GAME_VERSION {
+        reject(
+            &mut websocket,
+            format!(
+                "Version mismatch: the server runs version {} and the client version {}",
+                GAME_VERSION, hello.version
+            ),
+        );
+        bail!("Version mismatch");
+    }
+    let Some(encoding) = hello
+        .encodings
+        .iter()
+        .copied()
+        .find(|encoding| SUPPORTED_ENCODINGS.contains(encoding))
+    else {
+        reject(
+            &mut websocket,
+            "None of the client's encodings are supported".to_string(),
+        );
+        bail!("No supported encoding");
+    };
+    println!(
+        "{} connected with {} ({:?})",
+        hello.agent_name.as_deref().unwrap_or("Unnamed agent"),
+        hello.client_name,
+        hello.mode
+    );
+    // The handshake is done, the match loop waits on the players itself
+    websocket.get_ref().set_read_timeout(None)?;
+
+    match hello.mode {
+        ClientMode::Play => send_hello(&mut websocket, &ServerHello::Accept { encoding })?,
+        ClientMode::Spectate(match_id) => return spectators.watch(match_id, encoding, websocket),
+    }
 
     let mut queue_lock = queue
         .lock()
         .map_err(|e: PoisonError<_>| anyhow::anyhow!("Mutex poisoned: {}", e))?;
     if let Some(websocket2) = queue_lock.take() {
         drop(queue_lock);
-        let match_id_bytes = rand::random::<u64>().to_be_bytes();
+        let match_id = rand::random::<u64>();
+        // Resolve the seed here so it can be recorded before the match starts
+        let mut match_config = config.match_config.clone();
+        let seed = *match_config.seed.get_or_insert_with(rand::random);
+        // Generated before the players are told about the map
+        match_config.generate_map(seed)?;
         let mut websockets = [websocket, websocket2];
+        let encoded_config = postcard::to_allocvec(&match_config)?;
         for (player_id, ws) in websockets.iter_mut().enumerate() {
-            ws.send(Message::binary(match_id_bytes.to_vec()))?;
+            ws.send(Message::binary(match_id.to_be_bytes().to_vec()))?;
             ws.send(Message::binary((player_id as u16).to_be_bytes().to_vec()))?;
+            ws.send(Message::binary(encoded_config.clone()))?;
         }
-        app::start_app(websockets)?;
+        println!(
+            "Starting match {} on {} with seed {}",
+            match_id, match_config.map.name, seed
+        );
+
+        let replay = match create_replay(config, &match_config, match_id, seed, &websockets) {
+            Ok(replay) => replay,
+            Err(e) => {
+                eprintln!("Not recording a replay for match {}: {:#}", match_id, e);
+                None
+            }
+        };
+        let broadcast = spectators.open(match_id, encoded_config);
+        app::start_app(websockets, match_config, replay, broadcast)?;
     } else {
         *queue_lock = Some(websocket);
     }
 
     Ok(())
 }
+
+fn create_replay(
+    config: &ServerConfig,
+    match_config: &MatchConfig,
+    match_id: u64,
+    seed: u64,
+    websockets: &[WebSocket<TcpStream>; 2],
+) -> anyhow::Result<Option<ReplayWriter>> {
+    let Some(dir) = &config.replay_dir else {
+        return Ok(None);
+    };
+    fs::create_dir_all(dir)?;
+
+    let players = [0, 1].map(|player_id: usize| ReplayPlayer {
+        id: PlayerID(player_id as u16),
+        address: websockets[player_id]
+            .get_ref()
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default(),
+    });
+    let header = ReplayHeader::new(match_id, seed, players, match_config.clone());
+
+    let path = dir.join(format!("{:016x}.replay", match_id));
+    println!("Recording replay to {}", path.display());
+    Ok(Some(ReplayWriter::create(&path, &header)?))
+}
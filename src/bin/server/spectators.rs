@@ -0,0 +1,106 @@
+use crate::{reject, send_hello};
+use agentduels::handshake::{Encoding, ServerHello};
+use anyhow::bail;
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::sync::mpsc::{Sender, channel};
+use std::sync::{Arc, Mutex};
+use tungstenite::{Bytes, Message, WebSocket};
+
+/// The spectators of every running match, by match ID
+#[derive(Clone, Default)]
+pub struct Spectators(Arc<Mutex<HashMap<u64, MatchFeed>>>);
+
+struct MatchFeed {
+    /// The encoded config of the match, sent to spectators before the snapshot
+    config: Vec<u8>,
+    /// Spectators that joined since the last tick and still need a snapshot of the world
+    joining: Vec<Sender<Bytes>>,
+    watchers: Vec<Sender<Bytes>>,
+}
+
+impl Spectators {
+    /// Makes a match watchable until the returned broadcast is dropped
+    pub fn open(&self, match_id: u64, config: Vec<u8>) -> MatchBroadcast {
+        self.0.lock().unwrap().insert(
+            match_id,
+            MatchFeed {
+                config,
+                joining: Vec::new(),
+                watchers: Vec::new(),
+            },
+        );
+        MatchBroadcast {
+            spectators: self.clone(),
+            match_id,
+        }
+    }
+
+    /// Streams the ticks of a match to a spectator until the match ends or the spectator disconnects
+    pub fn watch(
+        &self,
+        match_id: u64,
+        encoding: Encoding,
+        mut ws: WebSocket<TcpStream>,
+    ) -> anyhow::Result<()> {
+        let (tx, rx) = channel();
+        let config = self.0.lock().unwrap().get_mut(&match_id).map(|feed| {
+            feed.joining.push(tx);
+            feed.config.clone()
+        });
+        let Some(config) = config else {
+            reject(&mut ws, format!("No running match with ID {}", match_id));
+            bail!("Spectator asked for unknown match {}", match_id);
+        };
+        send_hello(&mut ws, &ServerHello::Accept { encoding })?;
+        println!("Spectator joined match {}", match_id);
+
+        ws.send(Message::binary(match_id.to_be_bytes().to_vec()))?;
+        ws.send(Message::binary(config))?;
+        // The snapshot comes first, followed by the ticks after it
+        for msg in rx {
+            ws.send(Message::binary(msg))?;
+        }
+
+        let _ = ws.close(None);
+        let _ = ws.flush();
+        Ok(())
+    }
+}
+
+/// Sends a match's ticks to its spectators. The spectators are disconnected when this is dropped.
+pub struct MatchBroadcast {
+    spectators: Spectators,
+    match_id: u64,
+}
+
+impl MatchBroadcast {
+    /// Sends a tick to the spectators. Spectators that joined since the last tick get the snapshot of
+    /// the world after it instead, which is only taken when someone joined.
+    pub fn send(&self, msg: &[u8], snapshot: impl FnOnce() -> Vec<u8>) {
+        let msg = Bytes::copy_from_slice(msg);
+        let mut feeds = self.spectators.0.lock().unwrap();
+        let Some(feed) = feeds.get_mut(&self.match_id) else {
+            return;
+        };
+        // Drop the spectators that disconnected
+        feed.watchers
+            .retain(|watcher| watcher.send(msg.clone()).is_ok());
+
+        if !feed.joining.is_empty() {
+            let snapshot = Bytes::from(snapshot());
+            let joined = feed
+                .joining
+                .drain(..)
+                .filter(|watcher| watcher.send(snapshot.clone()).is_ok())
+                .collect::<Vec<_>>();
+            feed.watchers.extend(joined);
+        }
+    }
+}
+
+impl Drop for MatchBroadcast {
+    fn drop(&mut self) {
+        self.spectators.0.lock().unwrap().remove(&self.match_id);
+    }
+}
@@ -1,8 +1,11 @@
+use crate::handshake::{ClientHello, ClientMode, Encoding, ServerHello};
+use crate::config::MatchConfig;
 use crate::player::PlayerID;
-use crate::GAME_VERSION;
+use crate::{ControlFormat, GAME_VERSION, SERVER_URL, WorldSnapshot};
 use anyhow::{bail, Context};
 use bevy::ecs::resource::Resource;
 use bevy::utils::default;
+use std::net::SocketAddr;
 use std::thread;
 use std::time::Duration;
 use tokio::runtime::Builder;
@@ -10,6 +13,56 @@ use workflow_websocket::client::{
     ConnectOptions, ConnectStrategy, Message, WebSocket, WebSocketConfig,
 };
 
+/// Connection settings shared by the clients, read from the command line or the environment
+#[derive(clap::Args, Debug, Clone)]
+pub struct ConnectionArgs {
+    /// Address the control socket for the agent listens on (port 0 picks a free port)
+    #[arg(long, env = "AGENTDUELS_CONTROL_ADDR")]
+    pub control_addr: Option<SocketAddr>,
+    /// What the control socket sends the agent every tick
+    #[arg(
+        long,
+        env = "AGENTDUELS_CONTROL_FORMAT",
+        value_enum,
+        default_value_t = ControlFormat::Events
+    )]
+    pub control_format: ControlFormat,
+    /// Websocket URL of the game server
+    #[arg(long, env = "AGENTDUELS_SERVER_URL", default_value = SERVER_URL)]
+    pub server_url: String,
+    /// Name of the agent playing, shown to the server
+    #[arg(long, env = "AGENTDUELS_AGENT_NAME")]
+    pub agent_name: Option<String>,
+}
+
+/// How the client introduces itself to the server
+#[derive(Resource, Debug, Clone)]
+pub struct ClientInfo {
+    pub client_name: String,
+    pub agent_name: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn new(client_name: &str, agent_name: Option<String>) -> Self {
+        ClientInfo {
+            client_name: client_name.to_string(),
+            agent_name,
+        }
+    }
+}
+
+/// The game server URL the client connects to when joining a game
+#[derive(Resource, Clone)]
+pub struct ServerUrl(pub String);
+
+/// When set, joining watches the match with this ID instead of playing
+#[derive(Resource, Clone, Copy)]
+pub struct SpectateMatch(pub u64);
+
+/// Why the last attempt to join a game failed, shown in the main menu
+#[derive(Resource, Clone)]
+pub struct ConnectionError(pub String);
+
 pub enum GameConnectionMessage {
     SendMessage(Message),
     Disconnect,
@@ -21,7 +74,14 @@ pub struct GameConnection {
     pub receiver_rx: std::sync::mpmc::Receiver<Message>,
     pub sender_tx: std::sync::mpmc::Sender<GameConnectionMessage>,
     pub match_id: u64,
+    /// Always player 0 for spectators
     pub player_id: PlayerID,
+    /// Spectators only receive ticks and never send actions
+    pub spectator: bool,
+    /// The rules and map of the match, announced by the server before the snapshot
+    pub config: MatchConfig,
+    /// The world as it was when the client connected, the ticks received build on it
+    pub snapshot: WorldSnapshot,
 }
 
 impl GameConnection {
@@ -31,9 +91,17 @@ impl GameConnection {
 }
 
 impl GameConnection {
-    pub async fn connect(url: &str) -> anyhow::Result<Self> {
-        let url = url.to_string();
-        let (tx, rx) = std::sync::mpsc::channel::<GameConnection>();
+    pub async fn connect(url: &str, info: ClientInfo) -> anyhow::Result<Self> {
+        Self::open(url.to_string(), info, ClientMode::Play).await
+    }
+
+    /// Connects to a running match as a spectator
+    pub async fn spectate(url: &str, info: ClientInfo, match_id: u64) -> anyhow::Result<Self> {
+        Self::open(url.to_string(), info, ClientMode::Spectate(match_id)).await
+    }
+
+    async fn open(url: String, info: ClientInfo, mode: ClientMode) -> anyhow::Result<Self> {
+        let (tx, rx) = std::sync::mpsc::channel::<anyhow::Result<GameConnection>>();
 
         thread::spawn(move || {
             let rt = Builder::new_current_thread()
@@ -41,7 +109,7 @@ impl GameConnection {
                 .build()
                 .expect("Failed to build Tokio runtime");
 
-            rt.block_on(async {
+            let result = rt.block_on(async {
                 let socket = WebSocket::new(
                     Some(url.as_str()),
                     Some(WebSocketConfig {
@@ -63,11 +131,33 @@ impl GameConnection {
                     bail!("Expected Open message on connect");
                 };
 
-                println!("Connected to server. Sending game version...");
+                println!("Connected to server. Sending hello...");
+                let hello = ClientHello {
+                    version: GAME_VERSION,
+                    client_name: info.client_name,
+                    agent_name: info.agent_name,
+                    mode,
+                    encodings: vec![Encoding::Postcard],
+                };
                 socket
-                    .send(Message::Binary(GAME_VERSION.to_be_bytes().to_vec()))
+                    .send(Message::Binary(postcard::to_allocvec(&hello)?))
                     .await?;
 
+                let msg = socket.recv().await?;
+                let Message::Binary(data) = msg else {
+                    bail!("The server closed the connection during the handshake");
+                };
+                match postcard::from_bytes::<ServerHello>(&data)
+                    .context("Invalid handshake answer from the server")?
+                {
+                    ServerHello::Accept { encoding } => {
+                        println!("Accepted by the server, using {:?}", encoding)
+                    }
+                    ServerHello::Reject { reason } => {
+                        bail!("The server rejected the connection: {}", reason)
+                    }
+                }
+
                 println!("Waiting for match ID...");
                 let msg = socket.recv().await?;
                 let Message::Binary(data) = msg else {
@@ -81,15 +171,39 @@ impl GameConnection {
                 ]);
                 println!("Match ID: {}", match_id);
 
+                let player_id = if let ClientMode::Spectate(spectated_id) = mode {
+                    if match_id != spectated_id {
+                        bail!("Server sent the wrong match ID");
+                    }
+                    println!("Spectating");
+                    PlayerID(0)
+                } else {
+                    let msg = socket.recv().await?;
+                    let Message::Binary(data) = msg else {
+                        bail!("Unexpected message: {:?}", msg);
+                    };
+                    if data.len() != 2 {
+                        bail!("Wrong data length for player ID");
+                    }
+                    let player_id = PlayerID(u16::from_be_bytes([data[0], data[1]]));
+                    println!("Player ID: {}", player_id.0);
+                    player_id
+                };
+
                 let msg = socket.recv().await?;
                 let Message::Binary(data) = msg else {
                     bail!("Unexpected message: {:?}", msg);
                 };
-                if data.len() != 2 {
-                    bail!("Wrong data length for player ID");
-                }
-                let player_id = PlayerID(u16::from_be_bytes([data[0], data[1]]));
-                println!("Player ID: {}", player_id.0);
+                let config = postcard::from_bytes::<MatchConfig>(&data)
+                    .context("Invalid match config from the server")?;
+                println!("Playing on {}", config.map.name);
+
+                let msg = socket.recv().await?;
+                let Message::Binary(data) = msg else {
+                    bail!("Unexpected message: {:?}", msg);
+                };
+                let snapshot = postcard::from_bytes::<WorldSnapshot>(&data)
+                    .context("Invalid world snapshot from the server")?;
 
                 let (receiver_tx, receiver_rx) = std::sync::mpmc::channel();
                 let socket_clone = socket.clone();
@@ -107,13 +221,16 @@ impl GameConnection {
 
                 let (sender_tx, sender_rx) = std::sync::mpmc::channel();
 
-                tx.send(GameConnection {
+                tx.send(Ok(GameConnection {
                     socket: socket.clone(),
                     match_id,
                     player_id,
+                    spectator: matches!(mode, ClientMode::Spectate(_)),
+                    config,
+                    snapshot,
                     receiver_rx,
                     sender_tx,
-                })
+                }))
                 .expect("Failed to send GameConnection");
 
                 loop {
@@ -143,11 +260,14 @@ impl GameConnection {
                 }
 
                 Ok(())
-            })
-            .unwrap();
+            });
+            // Errors after the connection was handed over have no one left to receive them
+            if let Err(e) = result {
+                let _ = tx.send(Err(e));
+            }
         });
 
         rx.recv()
-            .context("Failed to receive GameConnection from thread")
+            .context("Failed to receive GameConnection from thread")?
     }
 }
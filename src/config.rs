@@ -0,0 +1,160 @@
+use crate::map::{GeneratorParams, MapDef};
+use crate::player::{default_kit, Health, Item, PlayerID};
+use crate::world::BlockType;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// The actions used for a player that missed the deadline for a tick
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MissedTickActions {
+    /// Repeat the last actions the player sent
+    #[default]
+    RepeatLast,
+    /// Do nothing for the tick
+    Empty,
+}
+
+/// How a match that reaches the tick limit is decided
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TimeLimitOutcome {
+    /// The player with the higher score wins, a tied match is a draw
+    #[default]
+    HigherScore,
+    /// The match is always a draw
+    Draw,
+    /// The player with the higher score wins, a tied match continues until the next goal
+    SuddenDeath,
+}
+
+/// Which of the blocks placed by players each player can break
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BreakableTeamBlocks {
+    /// Blocks placed by either player
+    #[default]
+    Both,
+    /// Only blocks the player placed themselves
+    Own,
+    /// Only blocks the opponent placed
+    Opponent,
+}
+
+/// Gameplay rules for a single match
+#[derive(Resource, Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MatchConfig {
+    /// Goals a player needs to score to win the match
+    pub score_to_win: u16,
+    /// Health players (re)spawn with, also restored by golden apples
+    pub max_health: f32,
+    /// Items given to players on spawn and after every death
+    pub starting_kit: BTreeMap<Item, u16>,
+    /// Ticks a player can't be hurt again after taking damage
+    pub hurt_cooldown_ticks: u8,
+    /// Downward speed players can land with without getting hurt, in blocks per second
+    pub fall_damage_safe_speed: f32,
+    /// Damage for every block per second players land with above the safe speed (0 disables fall damage)
+    pub fall_damage_per_speed: f32,
+    /// Ticks it takes to break each block type of the map by hand, block types that aren't listed can't be broken
+    pub block_hardness: BTreeMap<BlockType, u32>,
+    /// Ticks it takes to break a block placed by a player by hand, whatever its type
+    pub placed_block_hardness: u32,
+    /// How many times faster than a bare hand each item breaks blocks, unlisted items break at hand speed
+    pub tool_speed: BTreeMap<Item, u32>,
+    pub breakable_team_blocks: BreakableTeamBlocks,
+    /// Ticks an arrow can lie in the ground before it is despawned
+    pub arrow_ground_ticks: usize,
+    /// Ticks the bow has to be drawn for a full strength shot
+    pub bow_charge_ticks: usize,
+    /// Speed of a fully drawn arrow, in blocks per second
+    pub arrow_speed: f32,
+    /// Damage of a fully drawn arrow, weaker shots are slower and deal less
+    pub arrow_damage: f32,
+    /// Ticks it takes to get a shot arrow back, up to the count in the starting kit (0 never gives them back)
+    pub arrow_regen_ticks: u64,
+    /// Milliseconds each player has to send their actions after a tick is sent (0 waits forever)
+    pub action_timeout_ms: u64,
+    pub missed_tick_actions: MissedTickActions,
+    /// Consecutive missed ticks after which a player forfeits the match (0 never forfeits)
+    pub max_missed_ticks: u32,
+    /// Ticks after which the match is decided by `time_limit_outcome` (0 means no limit)
+    pub tick_limit: u64,
+    pub time_limit_outcome: TimeLimitOutcome,
+    /// Seed for all randomness in the match, a random seed is picked for every match if unset
+    pub seed: Option<u64>,
+    /// The arena the match is played in
+    pub map: MapDef,
+    /// Generates the arena from these parameters instead of using `map`
+    pub generator: Option<GeneratorParams>,
+}
+
+impl Default for MatchConfig {
+    fn default() -> Self {
+        MatchConfig {
+            score_to_win: 5,
+            max_health: Health::default().0,
+            starting_kit: default_kit(),
+            hurt_cooldown_ticks: 10,
+            fall_damage_safe_speed: 10.0,
+            fall_damage_per_speed: 2.0,
+            // The map can't be dug through, only blocks placed by players can be broken
+            block_hardness: BTreeMap::new(),
+            placed_block_hardness: 20,
+            tool_speed: BTreeMap::from([(Item::Pickaxe, 3)]),
+            breakable_team_blocks: BreakableTeamBlocks::Both,
+            arrow_ground_ticks: 100,
+            bow_charge_ticks: 40,
+            arrow_speed: 50.0,
+            arrow_damage: 9.0,
+            arrow_regen_ticks: 210,
+            action_timeout_ms: 1000,
+            missed_tick_actions: MissedTickActions::RepeatLast,
+            max_missed_ticks: 10,
+            tick_limit: 0,
+            time_limit_outcome: TimeLimitOutcome::HigherScore,
+            seed: None,
+            map: MapDef::default(),
+            generator: None,
+        }
+    }
+}
+
+impl MatchConfig {
+    /// Ticks it takes `player_id` to break a block of this type by hand, `None` if they can't break it.
+    /// `placed_by` is the player that placed the block, `None` for blocks of the map.
+    pub fn break_ticks(
+        &self,
+        block_type: BlockType,
+        placed_by: Option<PlayerID>,
+        player_id: PlayerID,
+    ) -> Option<u32> {
+        let Some(owner) = placed_by else {
+            return self.block_hardness.get(&block_type).copied();
+        };
+        let allowed = match self.breakable_team_blocks {
+            BreakableTeamBlocks::Both => true,
+            BreakableTeamBlocks::Own => owner == player_id,
+            BreakableTeamBlocks::Opponent => owner != player_id,
+        };
+        allowed.then_some(self.placed_block_hardness)
+    }
+
+    /// How many ticks of breaking by hand a tick of breaking with `item` is worth
+    pub fn tool_speed(&self, item: Item) -> u32 {
+        self.tool_speed.get(&item).copied().unwrap_or(1)
+    }
+
+    /// Replaces the map with one from the generator, if there is one. The generator is cleared
+    /// afterwards, so the map is the same wherever the config is passed on to.
+    pub fn generate_map(&mut self, seed: u64) -> anyhow::Result<()> {
+        if let Some(generator) = self.generator.take() {
+            self.map = generator.generate(seed)?;
+        }
+        Ok(())
+    }
+
+    pub fn action_timeout(&self) -> Option<Duration> {
+        (self.action_timeout_ms > 0).then(|| Duration::from_millis(self.action_timeout_ms))
+    }
+}
@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+
+/// Encodings the tick stream can be sent in
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Postcard,
+}
+
+/// What a client connects to the server for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientMode {
+    /// Join the queue for the next match
+    Play,
+    /// Watch the running match with this ID
+    Spectate(u64),
+}
+
+/// The first message a client sends after connecting, encoded with postcard
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientHello {
+    /// The client's [`GAME_VERSION`](crate::GAME_VERSION), kept first so it can always be read
+    pub version: u32,
+    pub client_name: String,
+    pub agent_name: Option<String>,
+    pub mode: ClientMode,
+    /// Encodings the client can decode, in order of preference
+    pub encodings: Vec<Encoding>,
+}
+
+/// The server's answer to a [`ClientHello`]. The server closes the connection after a rejection.
+/// Once the match starts, an accepted client gets the match ID, its player ID (only when playing),
+/// the [`MatchConfig`](crate::config::MatchConfig) of the match with its map and the [`WorldSnapshot`](crate::WorldSnapshot)
+/// to start from.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ServerHello {
+    Accept { encoding: Encoding },
+    Reject { reason: String },
+}
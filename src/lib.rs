@@ -1,26 +1,36 @@
 #![feature(mpmc_channel)]
 
-use crate::player::{Inventory, PlayerAnimation, PlayerID};
-use crate::states::network::ControlMsgC2S;
-use crate::world::BlockType;
+use crate::config::MatchConfig;
+use crate::map::MapDef;
+use crate::player::{Inventory, PlayerActions, PlayerAnimation, PlayerID, PlayerStance};
+use crate::states::network::{ControlMsgC2S, ControlMsgS2C};
+use crate::world::{BlockType, ChunkMap};
 use avian3d::prelude::PhysicsLayer;
 use bevy::math::{IVec3, Vec3};
 use bevy::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use std::io::{Read, Write};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
+pub mod agent;
 pub mod client;
+pub mod config;
+pub mod handshake;
+pub mod map;
 pub mod player;
+pub mod replay;
+pub mod sim;
 pub mod states;
 pub mod world;
 
-pub const GAME_VERSION: u32 = 0;
+pub const GAME_VERSION: u32 = 13;
 pub const SERVER_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8081);
 pub const SERVER_URL: &str = "ws://127.0.0.1:8081";
+/// Ticks per second of game time, the server steps physics by exactly one tick's worth each tick
+pub const TICK_RATE: u32 = 60;
 
 #[derive(Component, Serialize, Deserialize, Default, Debug, Clone)]
 pub struct PlayerInfo {
@@ -32,10 +42,69 @@ pub struct PlayerInfo {
     pub inventory_update: Option<Inventory>,
     pub animation: PlayerAnimation,
     pub hurt_update: Option<bool>,
+    /// How far the bow is drawn, from 0 to 1
+    pub bow_charge: f32,
+    /// The block the player is breaking, if any
+    pub breaking: Option<BlockBreaking>,
+    pub stance: PlayerStance,
+    /// How strong an attack with the held item would be, from 0 to 1
+    pub attack_charge: f32,
+}
+
+/// A block a player is in the middle of breaking
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct BlockBreaking {
+    pub block_pos: IVec3,
+    /// How far the block is broken, from 0 to 1
+    pub progress: f32,
+}
+
+impl PlayerInfo {
+    /// The player's current state without the changes of the tick it was sent with
+    pub fn without_changes(&self) -> Self {
+        PlayerInfo {
+            health_update: None,
+            inventory_update: None,
+            hurt_update: None,
+            ..self.clone()
+        }
+    }
 }
 
 pub type ArrowID = u32;
 
+/// What took health from a player
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum DamageSource {
+    /// Hit by the player with this ID
+    Melee(PlayerID),
+    /// Shot by the player with this ID
+    Arrow(PlayerID),
+    Fall,
+    /// Fell out of the world
+    Void,
+}
+
+/// A melee hit that landed during a tick
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Hit {
+    pub attacker: PlayerID,
+    pub target: PlayerID,
+    pub damage: f32,
+    /// Velocity added to the target
+    pub knockback: Vec3,
+    /// Landed while the attacker was falling, for extra damage
+    pub critical: bool,
+}
+
+/// Health a player lost during a tick
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Damage {
+    pub player_id: PlayerID,
+    pub amount: f32,
+    pub source: DamageSource,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum ArrowEvent {
     Updated {
@@ -46,27 +115,149 @@ pub enum ArrowEvent {
     Despawned(ArrowID),
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct TickMessage {
     pub tick: u64,
     pub players: [PlayerInfo; 2],
-    pub deaths: HashSet<PlayerID>,
+    /// The actions each player's tick was simulated with
+    pub actions: [PlayerActions; 2],
+    pub deaths: BTreeSet<PlayerID>,
     pub goals: Option<PlayerID>,
     pub block_updates: Vec<(IVec3, BlockType)>,
     pub arrow_events: Vec<ArrowEvent>,
+    /// Every time a player lost health during the tick
+    pub damage: Vec<Damage>,
+    pub hits: Vec<Hit>,
     pub game_results: Option<GameResults>,
+    /// Ticks left until the time limit, `None` if the match has no limit or is in sudden death
+    pub ticks_remaining: Option<u64>,
+    /// Set once the time limit was reached with tied scores, the next goal wins
+    pub sudden_death: bool,
+}
+
+/// The whole state of a match, so clients and agents that weren't there from the start can catch up.
+/// Sent to players before the first tick, to spectators when they join and to agents when they attach.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WorldSnapshot {
+    /// The first tick that isn't included yet
+    pub tick: u64,
+    /// Changes (like `health_update`) are always empty, the current values are in the other fields
+    pub players: [PlayerInfo; 2],
+    pub health: [f32; 2],
+    pub inventories: [Inventory; 2],
+    pub scores: [u16; 2],
+    pub map: ChunkMap,
+    /// Position and rotation of every arrow in flight
+    pub arrows: BTreeMap<ArrowID, (Vec3, Quat)>,
+}
+
+impl WorldSnapshot {
+    /// The state before the first tick of a match with the given rules
+    pub fn initial(config: &MatchConfig) -> Self {
+        WorldSnapshot {
+            tick: 0,
+            players: config.map.spawns.map(|spawn| PlayerInfo {
+                position: spawn.translation(),
+                yaw: spawn.yaw,
+                ..default()
+            }),
+            health: [config.max_health; 2],
+            inventories: [0, 1].map(|_| Inventory::new(config.starting_kit.clone())),
+            scores: [0; 2],
+            map: ChunkMap::from_map(&config.map),
+            arrows: BTreeMap::new(),
+        }
+    }
+
+    /// Brings the snapshot up to date with the next tick
+    pub fn apply(&mut self, msg: &TickMessage) {
+        self.tick = msg.tick + 1;
+        for (i, info) in msg.players.iter().enumerate() {
+            if let Some(health) = info.health_update {
+                self.health[i] = health;
+            }
+            if let Some(inventory) = &info.inventory_update {
+                self.inventories[i] = inventory.clone();
+            }
+            self.players[i] = info.without_changes();
+        }
+        if let Some(scorer) = msg.goals {
+            self.scores[scorer.0 as usize] += 1;
+        }
+        self.map.apply_diff(&msg.block_updates);
+        for arrow_event in msg.arrow_events.iter() {
+            match arrow_event {
+                ArrowEvent::Updated {
+                    id,
+                    position,
+                    rotation,
+                } => {
+                    self.arrows.insert(*id, (*position, *rotation));
+                }
+                ArrowEvent::Despawned(id) => {
+                    self.arrows.remove(id);
+                }
+            }
+        }
+    }
 }
 
 type ClientID = usize;
 
+/// Longest line accepted from an agent, agents that send longer ones are disconnected
+const MAX_CONTROL_LINE_LEN: u64 = 64 * 1024;
+
+/// The agent connected to the control socket.
+/// Messages are JSON objects, one per line in both directions.
+#[derive(Clone)]
+pub struct ControlClient(Arc<Mutex<TcpStream>>);
+
+impl ControlClient {
+    /// Writes a message followed by a newline.
+    /// Write errors are ignored since the reading thread notices when the agent disconnects.
+    pub fn send_line(&self, msg: &[u8]) {
+        let mut stream = self.0.lock().unwrap();
+        let _ = stream
+            .write_all(msg)
+            .and_then(|_| stream.write_all(b"\n"));
+    }
+
+    fn send_error(&self, error: String) {
+        self.send_line(&serde_json::to_vec(&ControlMsgS2C::Error(error)).unwrap());
+    }
+
+    fn shutdown(&self) {
+        let _ = self.0.lock().unwrap().shutdown(std::net::Shutdown::Both);
+    }
+}
+
+/// What the control socket sends the agent every tick
+#[derive(clap::ValueEnum, Debug, Default, Clone, Copy, PartialEq)]
+pub enum ControlFormat {
+    /// Typed events, see [`ControlMsgS2C`]
+    #[default]
+    Events,
+    /// `[player_id, tick]` with the whole [`TickMessage`] in one line
+    Raw,
+}
+
 #[derive(Resource)]
 pub struct ControlServer {
     listener: TcpListener,
-    client: Option<TcpStream>,
+    client: Option<ControlClient>,
+    format: ControlFormat,
     client_id: ClientID,
     disconnect_queue: Arc<Mutex<Vec<ClientID>>>,
     message_buffer: Arc<Mutex<Vec<ControlMsgC2S>>>,
     tick_start_messages: Option<Vec<u8>>,
+    /// The world before the tick the agent is answering, sent to agents that attach mid-match
+    world: Option<WorldSnapshot>,
+    /// The tick the agent is answering, applied to `world` once it has answered
+    pending_tick: Option<TickMessage>,
+    /// The map of the match, sent along with `world`
+    map: Option<MapDef>,
+    /// The agent attached between two ticks, so it gets the world before the next `TickStart`
+    send_world: bool,
 }
 
 impl ControlServer {
@@ -74,12 +265,30 @@ impl ControlServer {
         ControlServer {
             listener,
             client: None,
+            format: ControlFormat::default(),
             client_id: 0,
             disconnect_queue: Arc::new(Mutex::new(Vec::new())),
             message_buffer: Arc::new(Mutex::new(Vec::new())),
             tick_start_messages: None,
+            world: None,
+            map: None,
+            pending_tick: None,
+            send_world: false,
         }
     }
+
+    /// Binds a non-blocking control listener to the given address
+    pub fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        println!("Control server listening on {}", listener.local_addr()?);
+        Ok(Self::new(listener))
+    }
+
+    pub fn with_format(mut self, format: ControlFormat) -> Self {
+        self.format = format;
+        self
+    }
 }
 
 #[derive(States, Debug, Clone, PartialEq, Eq, Hash)]
@@ -88,45 +297,88 @@ pub enum AppState {
     Joining,
     Game,
     EndMenu,
+    Replay,
 }
 
 #[derive(Component)]
 pub struct AutoDespawn(pub AppState);
 
 pub fn handle_connection(mut server: ResMut<ControlServer>) {
-    let Ok((mut stream, _)) = server.listener.accept() else {
+    let Ok((stream, _)) = server.listener.accept() else {
+        return;
+    };
+    // Accepted streams must block, the reading thread waits on them
+    let _ = stream.set_nonblocking(false);
+    let Ok(reader) = stream.try_clone() else {
         return;
     };
-    if let Some(client) = &server.client {
-        let _ = client.shutdown(std::net::Shutdown::Both);
+    let client = ControlClient(Arc::new(Mutex::new(stream)));
+    if let Some(old_client) = server.client.replace(client.clone()) {
+        old_client.shutdown();
     }
-    server.client = Some(stream.try_clone().unwrap());
     server.client_id += 1;
     let client_id = server.client_id;
     let disconnect_queue = server.disconnect_queue.clone();
     let message_buffer = server.message_buffer.clone();
     let tick_start_messages = server.tick_start_messages.clone();
+    // In the middle of a tick the world and the tick are sent right away, nothing else is sent until
+    // the agent answers. Between ticks the next tick could overtake them, so the world goes with it.
+    let world = tick_start_messages.as_ref().and(server.world.clone());
+    server.send_world = tick_start_messages.is_none();
+    let map = server.map.clone();
     thread::spawn(move || {
+        if let Some(world) = world {
+            if let Some(map) = map {
+                client.send_line(
+                    serde_json::to_string(&ControlMsgS2C::Map(map))
+                        .unwrap()
+                        .as_bytes(),
+                );
+            }
+            client.send_line(
+                serde_json::to_string(&ControlMsgS2C::Snapshot(world))
+                    .unwrap()
+                    .as_bytes(),
+            );
+        }
         if let Some(message) = tick_start_messages {
-            stream.write(message.as_slice()).unwrap();
+            client.send_line(message.as_slice());
         }
         println!("Client {client_id} connected");
-        let mut buf = [0; 128];
+        let mut reader = BufReader::new(reader);
+        let mut line = Vec::new();
         loop {
-            let n = stream.read(&mut buf).unwrap_or(0);
+            line.clear();
+            let n = (&mut reader)
+                .take(MAX_CONTROL_LINE_LEN)
+                .read_until(b'\n', &mut line)
+                .unwrap_or(0);
             if n == 0 {
-                println!("Client {client_id} disconnected");
-                disconnect_queue.lock().unwrap().push(client_id);
                 break;
             }
-            for msg in serde_json::Deserializer::from_slice(&buf[..n])
-                .into_iter::<ControlMsgC2S>()
-                .flatten()
-            {
-                message_buffer.lock().unwrap().push(msg);
+            if n as u64 == MAX_CONTROL_LINE_LEN && !line.ends_with(b"\n") {
+                client.send_error(format!(
+                    "Messages can't be longer than {} bytes",
+                    MAX_CONTROL_LINE_LEN
+                ));
+                client.shutdown();
+                break;
+            }
+            let msg = line.trim_ascii();
+            if msg.is_empty() {
+                continue;
+            }
+            match serde_json::from_slice::<ControlMsgC2S>(msg) {
+                Ok(msg) => message_buffer.lock().unwrap().push(msg),
+                Err(e) => client.send_error(format!(
+                    "Invalid message {:?}: {}",
+                    String::from_utf8_lossy(msg),
+                    e
+                )),
             }
-            buf.fill(0);
         }
+        println!("Client {client_id} disconnected");
+        disconnect_queue.lock().unwrap().push(client_id);
     });
 }
 
@@ -155,11 +407,16 @@ pub enum CollisionLayer {
 
 pub const ARROW_HEIGHT: f32 = 0.5;
 pub const ARROW_WIDTH: f32 = 0.5;
+/// How many times stronger than the physics gravity arrows are pulled down
+pub const ARROW_GRAVITY_SCALE: f32 = 5.0;
 
 #[derive(Component, Default)]
 pub struct Arrow {
     pub id: u32,
+    pub shooter: PlayerID,
     pub ticks_in_ground: usize,
+    /// Damage dealt to the player it hits, set by how far the bow was drawn
+    pub damage: f32,
 }
 
 #[derive(Resource, Serialize, Deserialize, Clone)]
@@ -1,43 +1,83 @@
-use agentduels::states::{EndMenuPlugin, GamePlugin, JoiningPlugin, MainMenuPlugin};
+use agentduels::client::{ClientInfo, ConnectionArgs, ServerUrl, SpectateMatch};
+use agentduels::states::{
+    EndMenuPlugin, GamePlugin, JoiningPlugin, MainMenuPlugin, ReplayPlayback, ReplayPlugin,
+};
 use agentduels::{AppState, AutoDespawn, ControlServer, handle_connection, handle_disconnects};
 use bevy::prelude::*;
 use bevy_inspector_egui::bevy_egui::EguiPlugin;
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use clap::Parser;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 
 const CONTROL_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8082);
 
+#[derive(Parser)]
+#[command(about = "AgentDuels client")]
+struct Args {
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    /// Watch a recorded match instead of joining games
+    #[arg(long)]
+    replay: Option<PathBuf>,
+    /// Watch the running match with this ID instead of playing
+    #[arg(long, conflicts_with = "replay")]
+    spectate: Option<u64>,
+}
+
 #[tokio::main]
 async fn main() {
-    let listener = TcpListener::bind(CONTROL_ADDR).unwrap();
-    listener.set_nonblocking(true).unwrap();
+    let args = Args::parse();
+    // Watching doesn't need an agent, so leave the default port to a client that might be running
+    let default_control_addr = if args.replay.is_some() || args.spectate.is_some() {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)
+    } else {
+        CONTROL_ADDR
+    };
+    let control_server =
+        ControlServer::bind(args.connection.control_addr.unwrap_or(default_control_addr))
+            .unwrap()
+            .with_format(args.connection.control_format);
+
+    let mut app = App::new();
+    app.add_plugins((
+        DefaultPlugins.set(ImagePlugin::default_nearest()),
+        #[cfg(debug_assertions)]
+        EguiPlugin::default(),
+        #[cfg(debug_assertions)]
+        WorldInspectorPlugin::new(),
+    ))
+    .insert_resource(GlobalAmbientLight {
+        brightness: 400.0,
+        ..default()
+    })
+    .add_plugins((
+        MainMenuPlugin,
+        JoiningPlugin,
+        EndMenuPlugin,
+        GamePlugin::new(false),
+        ReplayPlugin,
+    ))
+    .insert_resource(control_server)
+    .insert_resource(ClientInfo::new("agentduels", args.connection.agent_name))
+    .insert_resource(ServerUrl(args.connection.server_url))
+    .add_systems(FixedUpdate, (handle_connection, handle_disconnects))
+    .add_systems(OnExit(AppState::Joining), cleanup_state)
+    .add_systems(OnExit(AppState::MainMenu), cleanup_state)
+    .add_systems(OnExit(AppState::Game), cleanup_state)
+    .add_systems(OnExit(AppState::EndMenu), cleanup_state);
+
+    if let Some(path) = &args.replay {
+        app.insert_resource(ReplayPlayback::load(path).unwrap())
+            .insert_state(AppState::Replay);
+    } else if let Some(match_id) = args.spectate {
+        app.insert_resource(SpectateMatch(match_id))
+            .insert_state(AppState::Joining);
+    } else {
+        app.insert_state(AppState::MainMenu);
+    }
 
-    App::new()
-        .add_plugins((
-            DefaultPlugins.set(ImagePlugin::default_nearest()),
-            #[cfg(debug_assertions)]
-            EguiPlugin::default(),
-            #[cfg(debug_assertions)]
-            WorldInspectorPlugin::new(),
-        ))
-        .insert_resource(GlobalAmbientLight {
-            brightness: 400.0,
-            ..default()
-        })
-        .insert_state(AppState::MainMenu)
-        .add_plugins((
-            MainMenuPlugin,
-            JoiningPlugin,
-            EndMenuPlugin,
-            GamePlugin::new(false),
-        ))
-        .insert_resource(ControlServer::new(listener))
-        .add_systems(FixedUpdate, (handle_connection, handle_disconnects))
-        .add_systems(OnExit(AppState::Joining), cleanup_state)
-        .add_systems(OnExit(AppState::MainMenu), cleanup_state)
-        .add_systems(OnExit(AppState::Game), cleanup_state)
-        .add_systems(OnExit(AppState::EndMenu), cleanup_state)
-        .run();
+    app.run();
 }
 
 fn cleanup_state(
@@ -0,0 +1,205 @@
+//! Arenas described as data, so matches can be played on other maps than the built-in one
+
+use crate::player::{PLAYER_HEIGHT, PlayerID};
+use crate::world::BlockType;
+use anyhow::{Context, bail};
+use bevy::math::{IVec3, Vec3};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+mod generator;
+
+pub use generator::*;
+
+/// A box of blocks, both corners included
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockBox {
+    pub min: IVec3,
+    pub max: IVec3,
+}
+
+impl BlockBox {
+    pub fn new(min: IVec3, max: IVec3) -> Self {
+        BlockBox { min, max }
+    }
+
+    pub fn contains(&self, pos: IVec3) -> bool {
+        pos.cmpge(self.min).all() && pos.cmple(self.max).all()
+    }
+
+    pub fn contains_box(&self, other: &BlockBox) -> bool {
+        self.contains(other.min) && self.contains(other.max)
+    }
+
+    /// The center of the box in world space, where every block spans from its position to the next one
+    pub fn center(&self) -> Vec3 {
+        (self.min.as_vec3() + self.max.as_vec3() + Vec3::ONE) / 2.0
+    }
+
+    pub fn positions(&self) -> impl Iterator<Item = IVec3> + use<> {
+        let (min, max) = (self.min, self.max);
+        (min.x..=max.x).flat_map(move |x| {
+            (min.y..=max.y).flat_map(move |y| (min.z..=max.z).map(move |z| IVec3::new(x, y, z)))
+        })
+    }
+}
+
+/// Fills a box with one type of block
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Fill {
+    pub min: IVec3,
+    pub max: IVec3,
+    pub block: BlockType,
+}
+
+impl Fill {
+    pub fn area(&self) -> BlockBox {
+        BlockBox::new(self.min, self.max)
+    }
+}
+
+/// Where a player (re)spawns
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Spawn {
+    /// Position of the player's feet
+    pub position: Vec3,
+    /// Rotation around the y axis, in radians
+    pub yaw: f32,
+}
+
+impl Spawn {
+    /// The translation of a player standing at the spawn, which is the center of their collider
+    pub fn translation(&self) -> Vec3 {
+        self.position + Vec3::Y * (PLAYER_HEIGHT / 2.0)
+    }
+}
+
+/// Everything that makes up an arena. Loaded from TOML or JSON files and sent to the clients when a match starts.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct MapDef {
+    pub name: String,
+    /// Applied in order, so later fills overwrite earlier ones and fills with air carve holes
+    pub fills: Vec<Fill>,
+    /// Indexed by player ID
+    pub spawns: [Spawn; 2],
+    /// The goal each player scores in, indexed by player ID. Entering the other goal kills the player.
+    pub goals: [BlockBox; 2],
+    /// Height below which players die and arrows are despawned
+    pub kill_plane: f32,
+    /// Blocks can only exist inside these bounds, so players can't build outside of them
+    pub build_limits: BlockBox,
+}
+
+impl Default for MapDef {
+    /// The classic arena: two islands with a goal hole each, joined by a bridge of both team colors
+    fn default() -> Self {
+        let goals = [
+            BlockBox::new(IVec3::new(-27, -3, -1), IVec3::new(-25, -1, 1)),
+            BlockBox::new(IVec3::new(25, -3, -1), IVec3::new(27, -1, 1)),
+        ];
+        let mut fills = vec![
+            Fill {
+                min: IVec3::new(-20, -8, 0),
+                max: IVec3::new(-1, 0, 0),
+                block: BlockType::BlueBlock,
+            },
+            Fill {
+                min: IVec3::new(0, -8, 0),
+                max: IVec3::new(0, 0, 0),
+                block: BlockType::WhiteBlock,
+            },
+            Fill {
+                min: IVec3::new(1, -8, 0),
+                max: IVec3::new(20, 0, 0),
+                block: BlockType::RedBlock,
+            },
+        ];
+        for (min_x, max_x) in [(-30, -21), (21, 30)] {
+            for (min_y, max_y, block) in [
+                (-5, -3, BlockType::Stone),
+                (-2, -1, BlockType::Dirt),
+                (0, 0, BlockType::Grass),
+            ] {
+                fills.push(Fill {
+                    min: IVec3::new(min_x, min_y, -5),
+                    max: IVec3::new(max_x, max_y, 5),
+                    block,
+                });
+            }
+        }
+        // The holes reach up to the surface
+        for goal in goals.iter() {
+            fills.push(Fill {
+                min: goal.min,
+                max: goal.max + IVec3::Y,
+                block: BlockType::Air,
+            });
+        }
+
+        MapDef {
+            name: "Classic".to_string(),
+            fills,
+            spawns: [
+                Spawn {
+                    position: Vec3::new(21.5, 1.0, 0.5),
+                    yaw: std::f32::consts::PI,
+                },
+                Spawn {
+                    position: Vec3::new(-20.5, 1.0, 0.5),
+                    yaw: 0.0,
+                },
+            ],
+            goals,
+            kill_plane: -10.0,
+            build_limits: BlockBox::new(IVec3::new(-32, -16, -16), IVec3::new(47, 31, 31)),
+        }
+    }
+}
+
+impl MapDef {
+    /// Reads a map file, as JSON if the extension is `json` and as TOML otherwise
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read map file {}", path.display()))?;
+        let map: MapDef = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&contents)
+                .with_context(|| format!("Invalid map file {}", path.display()))?
+        } else {
+            toml::from_str(&contents)
+                .with_context(|| format!("Invalid map file {}", path.display()))?
+        };
+        map.validate()
+            .with_context(|| format!("Invalid map file {}", path.display()))?;
+        Ok(map)
+    }
+
+    /// Checks that every fill and goal lies within the build limits
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for fill in self.fills.iter() {
+            if !self.build_limits.contains_box(&fill.area()) {
+                bail!(
+                    "The fill from {} to {} is outside the build limits",
+                    fill.min,
+                    fill.max
+                );
+            }
+        }
+        for (i, goal) in self.goals.iter().enumerate() {
+            if !self.build_limits.contains_box(goal) {
+                bail!("The goal of player {} is outside the build limits", i);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn spawn(&self, player_id: PlayerID) -> &Spawn {
+        &self.spawns[player_id.0 as usize]
+    }
+
+    /// The goal `player_id` scores in
+    pub fn goal(&self, player_id: PlayerID) -> &BlockBox {
+        &self.goals[player_id.0 as usize]
+    }
+}
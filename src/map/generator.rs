@@ -0,0 +1,334 @@
+//! Generates duel arenas from a seed, for training agents on more than one layout
+
+use super::{BlockBox, Fill, MapDef, Spawn};
+use crate::world::BlockType;
+use anyhow::Context;
+use bevy::math::{IVec3, Vec3};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Height of the top of the islands and the bridge, players spawn on top of it
+const SURFACE_Y: i32 = 0;
+/// Lowest blocks of the bridge
+const BRIDGE_BOTTOM_Y: i32 = -8;
+/// Space around the arena players can still build in
+const BUILD_MARGIN: i32 = 8;
+/// Highest block players can build at
+const BUILD_LIMIT_Y: i32 = 31;
+/// Longest bridge, islands and obstacles, larger parameters are clamped to these
+const MAX_BRIDGE_LENGTH: u32 = 200;
+const MAX_ISLAND_SIZE: u32 = 64;
+const MAX_OBSTACLE_HEIGHT: u32 = (BUILD_LIMIT_Y - SURFACE_Y - BUILD_MARGIN) as u32;
+
+/// What the arenas from [`GeneratorParams::generate`] look like. The defaults generate the classic arena.
+///
+/// Only player 0's half of the arena is generated, player 1's half is its mirror image
+/// (with the team colors swapped), so neither player has an advantage.
+/// Lengths and counts too large to fit in the arena are clamped.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct GeneratorParams {
+    /// Seed of the arena, the match seed is used if unset
+    pub seed: Option<u64>,
+    /// Blocks of bridge between the middle of the arena and each island
+    pub bridge_length: u32,
+    /// Blocks of bridge side by side
+    pub bridge_width: u32,
+    /// Holes in each half of the bridge, which have to be built across
+    pub bridge_gaps: u32,
+    /// Longest hole in the bridge, in blocks
+    pub max_gap_length: u32,
+    /// Size of the islands along the bridge
+    pub island_length: u32,
+    /// Size of the islands across the bridge
+    pub island_width: u32,
+    /// Chance for every column on the edge of an island to be left out, for less regular shapes
+    pub island_roughness: f32,
+    /// Stone pillars on each island
+    pub obstacles: u32,
+    pub obstacle_height: u32,
+    /// Puts the goals at a random spot of the islands instead of their middle
+    pub random_goals: bool,
+}
+
+impl Default for GeneratorParams {
+    fn default() -> Self {
+        GeneratorParams {
+            seed: None,
+            bridge_length: 20,
+            bridge_width: 1,
+            bridge_gaps: 0,
+            max_gap_length: 3,
+            island_length: 10,
+            island_width: 11,
+            island_roughness: 0.0,
+            obstacles: 0,
+            obstacle_height: 3,
+            random_goals: false,
+        }
+    }
+}
+
+impl GeneratorParams {
+    /// Generates an arena, always the same one for the same parameters and seed.
+    /// `seed` is only used if the parameters don't have a seed of their own.
+    pub fn generate(&self, seed: u64) -> anyhow::Result<MapDef> {
+        let mut rng = fastrand::Rng::with_seed(self.seed.unwrap_or(seed));
+        let bridge_length = self.bridge_length.clamp(1, MAX_BRIDGE_LENGTH) as i32;
+        let island_length = self.island_length.clamp(6, MAX_ISLAND_SIZE) as i32;
+        let island_width = self.island_width.clamp(5, MAX_ISLAND_SIZE) as i32;
+        let bridge_width = (self.bridge_width.max(1) as i32).min(island_width);
+        let bridge_gaps = self.bridge_gaps.min(bridge_length as u32);
+        let max_gap_length = self.max_gap_length.clamp(1, bridge_length as u32) as i32;
+        let obstacles = self.obstacles.min((island_length * island_width) as u32);
+        let obstacle_height = self.obstacle_height.min(MAX_OBSTACLE_HEIGHT) as i32;
+
+        // Player 0's half, keyed by (x, z, y) so the blocks of a column are next to each other
+        let mut blocks: BTreeMap<(i32, i32, i32), BlockType> = BTreeMap::new();
+        let bridge_z = -(bridge_width - 1) / 2..=bridge_width / 2;
+        let island_x = bridge_length + 1..=bridge_length + island_length;
+        let island_z = -(island_width - 1) / 2..=island_width / 2;
+
+        let mut gaps = Vec::new();
+        for _ in 0..bridge_gaps {
+            let length = rng.i32(1..=max_gap_length);
+            // The first block of the bridge stays, so the middle of the arena can be reached from both sides
+            let start = rng.i32(2..=(bridge_length - length + 1).max(2));
+            gaps.push(start..start + length);
+        }
+        for x in 1..=bridge_length {
+            if gaps.iter().any(|gap| gap.contains(&x)) {
+                continue;
+            }
+            for z in bridge_z.clone() {
+                for y in BRIDGE_BOTTOM_Y..=SURFACE_Y {
+                    blocks.insert((x, z, y), BlockType::RedBlock);
+                }
+            }
+        }
+
+        let goal_length = 3;
+        let (goal_x, goal_z) = if self.random_goals {
+            // Keep a ring of island around the goal, and the spawn column free
+            (
+                rng.i32(*island_x.start() + 2..=*island_x.end() - goal_length),
+                rng.i32(*island_z.start() + 1..=*island_z.end() - goal_length),
+            )
+        } else {
+            (*island_x.start() + island_length / 2 - 1, -1)
+        };
+        let goal = BlockBox::new(
+            IVec3::new(goal_x, SURFACE_Y - 3, goal_z),
+            IVec3::new(
+                goal_x + goal_length - 1,
+                SURFACE_Y - 1,
+                goal_z + goal_length - 1,
+            ),
+        );
+        let around_goal = BlockBox::new(
+            goal.min - IVec3::new(1, 0, 1),
+            goal.max + IVec3::new(1, 1, 1),
+        );
+
+        for x in island_x.clone() {
+            for z in island_z.clone() {
+                let edge = x == *island_x.start()
+                    || x == *island_x.end()
+                    || z == *island_z.start()
+                    || z == *island_z.end();
+                // The bridge has to stay connected to the spawn
+                let spawn_lane = x == *island_x.start() && bridge_z.contains(&z);
+                if edge && !spawn_lane && rng.f32() < self.island_roughness {
+                    continue;
+                }
+                for y in SURFACE_Y - 5..=SURFACE_Y {
+                    let block = match SURFACE_Y - y {
+                        0 => BlockType::Grass,
+                        1..=2 => BlockType::Dirt,
+                        _ => BlockType::Stone,
+                    };
+                    // The hole reaches up to the surface
+                    if !(goal.contains(IVec3::new(x, y, z))
+                        || goal.contains(IVec3::new(x, y - 1, z)))
+                    {
+                        blocks.insert((x, z, y), block);
+                    }
+                }
+            }
+        }
+
+        for _ in 0..obstacles {
+            let x = rng.i32(*island_x.start() + 1..=*island_x.end());
+            let z = rng.i32(island_z.clone());
+            let pos = IVec3::new(x, SURFACE_Y, z);
+            if bridge_z.contains(&z)
+                || around_goal.contains(pos)
+                || !blocks.contains_key(&(x, z, SURFACE_Y))
+            {
+                continue;
+            }
+            for y in SURFACE_Y + 1..=SURFACE_Y + obstacle_height {
+                blocks.insert((x, z, y), BlockType::Stone);
+            }
+        }
+
+        // Player 1's half is the mirror image around x = 0.5, blocks at x sit at -x
+        let half = blocks.clone();
+        for ((x, z, y), block) in half {
+            let block = match block {
+                BlockType::RedBlock => BlockType::BlueBlock,
+                block => block,
+            };
+            blocks.insert((-x, z, y), block);
+        }
+        for z in bridge_z.clone() {
+            for y in BRIDGE_BOTTOM_Y..=SURFACE_Y {
+                blocks.insert((0, z, y), BlockType::WhiteBlock);
+            }
+        }
+
+        let far_x = *island_x.end() + BUILD_MARGIN;
+        let far_z = island_width / 2 + BUILD_MARGIN;
+        let spawn_x = *island_x.start() as f32 + 0.5;
+        let map = MapDef {
+            name: format!("Generated {}", self.seed.unwrap_or(seed)),
+            fills: column_fills(&blocks),
+            spawns: [
+                Spawn {
+                    position: Vec3::new(spawn_x, (SURFACE_Y + 1) as f32, 0.5),
+                    yaw: std::f32::consts::PI,
+                },
+                Spawn {
+                    position: Vec3::new(1.0 - spawn_x, (SURFACE_Y + 1) as f32, 0.5),
+                    yaw: 0.0,
+                },
+            ],
+            // Each player scores in the goal on the other's island
+            goals: [
+                BlockBox::new(goal.min.with_x(-goal.max.x), goal.max.with_x(-goal.min.x)),
+                goal,
+            ],
+            kill_plane: -10.0,
+            build_limits: BlockBox::new(
+                IVec3::new(-far_x, -16, -far_z),
+                IVec3::new(far_x, BUILD_LIMIT_Y, far_z),
+            ),
+        };
+        map.validate().context("Generated an invalid map")?;
+        Ok(map)
+    }
+}
+
+/// Turns the blocks into one fill for every run of the same block in a column
+fn column_fills(blocks: &BTreeMap<(i32, i32, i32), BlockType>) -> Vec<Fill> {
+    let mut fills: Vec<Fill> = Vec::new();
+    for (&(x, z, y), &block) in blocks.iter() {
+        let pos = IVec3::new(x, y, z);
+        match fills.last_mut() {
+            Some(fill) if fill.block == block && fill.max + IVec3::Y == pos => fill.max = pos,
+            _ => fills.push(Fill {
+                min: pos,
+                max: pos,
+                block,
+            }),
+        }
+    }
+    fills
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// The block at every position the fills of the map leave solid
+    fn blocks(map: &MapDef) -> HashMap<IVec3, BlockType> {
+        let mut blocks = HashMap::new();
+        for fill in map.fills.iter() {
+            for pos in fill.area().positions() {
+                blocks.insert(pos, fill.block);
+            }
+        }
+        blocks.retain(|_, block| *block != BlockType::Air);
+        blocks
+    }
+
+    fn assert_mirrored(map: &MapDef) {
+        let blocks = blocks(map);
+        for (pos, block) in blocks.iter() {
+            let mirrored = match block {
+                BlockType::RedBlock => BlockType::BlueBlock,
+                BlockType::BlueBlock => BlockType::RedBlock,
+                block => *block,
+            };
+            assert_eq!(
+                blocks.get(&pos.with_x(-pos.x)),
+                Some(&mirrored),
+                "{} isn't mirrored",
+                pos
+            );
+        }
+
+        let [red, blue] = map.spawns;
+        assert_eq!(blue.position, red.position.with_x(1.0 - red.position.x));
+        assert_eq!(blue.yaw, std::f32::consts::PI - red.yaw);
+
+        let [red_goal, blue_goal] = map.goals;
+        assert_eq!(
+            red_goal,
+            BlockBox::new(
+                blue_goal.min.with_x(-blue_goal.max.x),
+                blue_goal.max.with_x(-blue_goal.min.x),
+            )
+        );
+        assert_eq!(map.build_limits.min.x, -map.build_limits.max.x);
+    }
+
+    fn rough_params() -> GeneratorParams {
+        GeneratorParams {
+            bridge_width: 3,
+            bridge_gaps: 4,
+            island_roughness: 0.5,
+            obstacles: 10,
+            random_goals: true,
+            ..GeneratorParams::default()
+        }
+    }
+
+    #[test]
+    fn generated_arenas_are_mirrored() {
+        assert_mirrored(&GeneratorParams::default().generate(0).unwrap());
+        for seed in 0..20 {
+            assert_mirrored(&rough_params().generate(seed).unwrap());
+        }
+    }
+
+    #[test]
+    fn same_seed_generates_same_arena() {
+        let params = rough_params();
+        assert_eq!(params.generate(7).unwrap(), params.generate(7).unwrap());
+        assert_ne!(params.generate(7).unwrap(), params.generate(8).unwrap());
+
+        let fixed = GeneratorParams {
+            seed: Some(3),
+            ..rough_params()
+        };
+        assert_eq!(fixed.generate(1).unwrap(), fixed.generate(2).unwrap());
+    }
+
+    #[test]
+    fn oversized_params_are_clamped() {
+        let params = GeneratorParams {
+            bridge_length: u32::MAX,
+            bridge_width: u32::MAX,
+            bridge_gaps: u32::MAX,
+            max_gap_length: u32::MAX,
+            island_length: u32::MAX,
+            island_width: u32::MAX,
+            obstacles: u32::MAX,
+            obstacle_height: u32::MAX,
+            ..rough_params()
+        };
+        assert_mirrored(&params.generate(0).unwrap());
+    }
+}
@@ -1,11 +1,24 @@
 use bevy::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 pub const PLAYER_HEIGHT: f32 = 1.8;
 pub const PLAYER_EYE_HEIGHT: f32 = 1.75;
 pub const PLAYER_WIDTH: f32 = 0.6;
 pub const PLAYER_SPEED: f32 = 3.0;
+pub const PLAYER_SPRINT_SPEED: f32 = 3.9;
+pub const PLAYER_SNEAK_SPEED: f32 = 0.9;
+/// Height of the hitbox of a sneaking player, its bottom stays at their feet
+pub const PLAYER_SNEAK_HEIGHT: f32 = 1.5;
+pub const PLAYER_SNEAK_EYE_HEIGHT: f32 = 1.45;
+/// Speed a melee hit knocks the target back with
+pub const ATTACK_KNOCKBACK: f32 = 10.0;
+/// How much harder sprinting players knock back the players they hit
+pub const SPRINT_KNOCKBACK_MULTIPLIER: f32 = 1.5;
+/// Damage multiplier of hits landed while falling
+pub const CRITICAL_HIT_MULTIPLIER: f32 = 1.5;
+/// Attack charge needed for critical hits and the sprint knockback bonus
+pub const STRONG_ATTACK_CHARGE: f32 = 0.9;
 pub const PLAYER_JUMP_SPEED: f32 = 10.0;
 pub const PLAYER_INTERACT_RANGE: f32 = 3.0;
 pub struct PlayerAnimationIndices {
@@ -15,6 +28,8 @@ pub struct PlayerAnimationIndices {
     pub swing: u32,
     pub draw_bow: u32,
     pub eat: u32,
+    pub sneak: u32,
+    pub sprint: u32,
 }
 pub const PLAYER_ANIMATION_INDICES: PlayerAnimationIndices = PlayerAnimationIndices {
     root: 0,
@@ -23,12 +38,9 @@ pub const PLAYER_ANIMATION_INDICES: PlayerAnimationIndices = PlayerAnimationIndi
     swing: 3,
     draw_bow: 4,
     eat: 5,
+    sneak: 6,
+    sprint: 7,
 };
-pub const SPAWN_POSITIONS: [Vec3; 2] = [
-    Vec3::new(21.5, 1.0 + PLAYER_HEIGHT / 2.0, 0.5),
-    Vec3::new(-20.5, 1.0 + PLAYER_HEIGHT / 2.0, 0.5),
-];
-pub const SPAWN_ROTATIONS: [f32; 2] = [std::f32::consts::PI, 0.0];
 
 /// ID 0 = self, ID 1 = opponent
 #[derive(
@@ -36,7 +48,7 @@ pub const SPAWN_ROTATIONS: [f32; 2] = [std::f32::consts::PI, 0.0];
 )]
 pub struct PlayerID(pub u16);
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd)]
 pub enum Item {
     Sword,
     Pickaxe,
@@ -61,12 +73,23 @@ impl Item {
         match self {
             Item::Sword => 0,
             Item::Pickaxe => 0,
-            Item::Bow => 40,
+            Item::Bow => 0,
             Item::Arrow => 0,
             Item::Block => 0,
             Item::GoldenApple => 20,
         }
     }
+    /// Ticks after an attack before the item hits with full strength again
+    pub fn attack_cooldown_ticks(&self) -> u32 {
+        match self {
+            Item::Sword => 36,
+            Item::Pickaxe => 48,
+            Item::Bow => 15,
+            Item::Arrow => 15,
+            Item::Block => 15,
+            Item::GoldenApple => 15,
+        }
+    }
     pub fn damage(&self) -> f32 {
         match self {
             Item::Sword => 4.0,
@@ -85,6 +108,23 @@ pub struct Rotation {
     pub pitch: f32,
 }
 
+impl Rotation {
+    /// The head rotation in world space. A yaw of 0 faces the opponent's side for both players.
+    pub fn to_quat(&self, player_id: PlayerID) -> Quat {
+        let yaw = if player_id.0 == 1 {
+            self.yaw
+        } else {
+            self.yaw + std::f32::consts::PI
+        };
+        let yaw = Quat::from_rotation_y(yaw);
+        let pitch = Quat::from_rotation_z(
+            self.pitch
+                .clamp(-std::f32::consts::FRAC_PI_2, std::f32::consts::FRAC_PI_2),
+        );
+        yaw * pitch
+    }
+}
+
 /// Bitflags representing player actions (Is reset every tick)
 #[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
 pub struct PlayerActions {
@@ -121,6 +161,10 @@ impl PlayerActions {
     pub const USE_ITEM: u16 = 1 << 6;
     pub const PLACE_BLOCK: u16 = 1 << 7;
     pub const DIG_BLOCK: u16 = 1 << 8;
+    /// Only speeds the player up while they move forward
+    pub const SPRINT: u16 = 1 << 9;
+    /// Takes precedence over sprinting
+    pub const SNEAK: u16 = 1 << 10;
     pub const HAND_ACTION_MASK: u16 =
         Self::ATTACK + Self::USE_ITEM + Self::PLACE_BLOCK + Self::DIG_BLOCK;
 
@@ -147,6 +191,58 @@ impl PlayerActions {
 #[derive(Component, Default, Clone, Copy)]
 pub struct PlayerActionsTracker(pub PlayerActions);
 
+/// How the player is moving, picked from their actions every tick
+#[derive(Component, Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayerStance {
+    #[default]
+    Standing,
+    /// Faster, and knocks back harder
+    Sprinting,
+    /// Slower with a lower hitbox, and won't walk off the edge of a block
+    Sneaking,
+}
+
+impl PlayerStance {
+    pub fn from_actions(actions: &PlayerActions) -> Self {
+        if actions.is_set(PlayerActions::SNEAK) {
+            PlayerStance::Sneaking
+        } else if actions.is_set(PlayerActions::SPRINT)
+            && actions.is_set(PlayerActions::MOVE_FORWARD)
+        {
+            PlayerStance::Sprinting
+        } else {
+            PlayerStance::Standing
+        }
+    }
+
+    pub fn speed(&self) -> f32 {
+        match self {
+            PlayerStance::Standing => PLAYER_SPEED,
+            PlayerStance::Sprinting => PLAYER_SPRINT_SPEED,
+            PlayerStance::Sneaking => PLAYER_SNEAK_SPEED,
+        }
+    }
+
+    pub fn height(&self) -> f32 {
+        match self {
+            PlayerStance::Sneaking => PLAYER_SNEAK_HEIGHT,
+            _ => PLAYER_HEIGHT,
+        }
+    }
+
+    pub fn eye_height(&self) -> f32 {
+        match self {
+            PlayerStance::Sneaking => PLAYER_SNEAK_EYE_HEIGHT,
+            _ => PLAYER_EYE_HEIGHT,
+        }
+    }
+
+    /// Where the eyes of a player at this position are, the position being the centre of a standing hitbox
+    pub fn eye_position(&self, position: Vec3) -> Vec3 {
+        position + Vec3::Y * (self.eye_height() - PLAYER_HEIGHT / 2.0)
+    }
+}
+
 #[derive(Component)]
 pub struct PlayerBody;
 
@@ -170,22 +266,38 @@ impl Default for Health {
 pub struct HurtCooldown(pub u8);
 
 impl HurtCooldown {
-    pub fn start(&mut self) {
-        self.0 = 10;
+    pub fn start(&mut self, ticks: u8) {
+        self.0 = ticks;
     }
 }
 
 #[derive(Component, Serialize, Deserialize, Clone, Debug)]
 pub struct Inventory {
-    contents: HashMap<Item, u16>,
+    contents: BTreeMap<Item, u16>,
     selected: Item,
 }
 
 impl Inventory {
+    pub fn new(contents: BTreeMap<Item, u16>) -> Self {
+        Inventory {
+            contents,
+            selected: Item::Sword,
+        }
+    }
+
     pub fn get_count(&self, item: Item) -> u16 {
         *self.contents.get(&item).unwrap_or(&0)
     }
 
+    pub fn contents(&self) -> &BTreeMap<Item, u16> {
+        &self.contents
+    }
+
+    pub fn add_item(&mut self, item: Item, amount: u16) {
+        let count = self.contents.entry(item).or_insert(0);
+        *count = count.saturating_add(amount);
+    }
+
     pub fn remove_item(&mut self, item: Item, amount: u16) {
         *self.contents.entry(item).or_insert(0) = self
             .contents
@@ -205,20 +317,22 @@ impl Inventory {
 
 impl Default for Inventory {
     fn default() -> Self {
-        let mut contents = HashMap::new();
+        Inventory::new(default_kit())
+    }
+}
 
-        contents.insert(Item::Sword, 1);
-        contents.insert(Item::Pickaxe, 1);
-        contents.insert(Item::Bow, 1);
-        contents.insert(Item::Arrow, 1);
-        contents.insert(Item::Block, 128);
-        contents.insert(Item::GoldenApple, 8);
+/// The items every player starts with unless the match config says otherwise
+pub fn default_kit() -> BTreeMap<Item, u16> {
+    let mut contents = BTreeMap::new();
 
-        Inventory {
-            contents,
-            selected: Item::Sword,
-        }
-    }
+    contents.insert(Item::Sword, 1);
+    contents.insert(Item::Pickaxe, 1);
+    contents.insert(Item::Bow, 1);
+    contents.insert(Item::Arrow, 1);
+    contents.insert(Item::Block, 128);
+    contents.insert(Item::GoldenApple, 8);
+
+    contents
 }
 
 #[derive(Component, Default)]
@@ -226,7 +340,9 @@ pub struct Score(pub u16);
 
 pub struct BreakingStatus {
     pub block_pos: IVec3,
-    pub ticks_left: usize,
+    /// Ticks of breaking by hand done so far, the block breaks once this reaches `hardness`
+    pub progress: u32,
+    pub hardness: u32,
 }
 
 /// Tracker for the player's block breaking status
@@ -251,6 +367,55 @@ impl ItemUsageStatus {
 #[derive(Component, Default)]
 pub struct ItemUsageStatusTracker(pub Option<ItemUsageStatus>);
 
+/// Ticks the player has been drawing the bow for, the arrow is shot when they stop
+#[derive(Component, Default)]
+pub struct BowCharge(pub usize);
+
+impl BowCharge {
+    /// How far the bow is drawn, from 0 to 1
+    pub fn level(&self, full_charge_ticks: usize) -> f32 {
+        (self.0 as f32 / full_charge_ticks.max(1) as f32).min(1.0)
+    }
+
+    /// The share of the full arrow speed and damage a shot would get, which grows faster than the draw
+    pub fn power(&self, full_charge_ticks: usize) -> f32 {
+        let level = self.level(full_charge_ticks);
+        (level * level + 2.0 * level) / 3.0
+    }
+}
+
+/// Tracks how far the player's next attack is charged
+#[derive(Component)]
+pub struct AttackCooldown {
+    pub ticks_since_attack: u32,
+    /// Set once a sprinting hit lands, the sprint knockback bonus only comes back after the player stops sprinting
+    pub sprint_hit: bool,
+}
+
+impl AttackCooldown {
+    /// How strong an attack with the item would be, from 0 to 1
+    pub fn charge(&self, item: Item) -> f32 {
+        (self.ticks_since_attack as f32 / item.attack_cooldown_ticks().max(1) as f32).min(1.0)
+    }
+}
+
+impl Default for AttackCooldown {
+    fn default() -> Self {
+        AttackCooldown {
+            ticks_since_attack: u32::MAX,
+            sprint_hit: false,
+        }
+    }
+}
+
+/// Fastest downward speed of the player since they last stood on the ground
+#[derive(Component, Default)]
+pub struct FallSpeed(pub f32);
+
+/// Ticks since the player last had a full stack of arrows
+#[derive(Component, Default)]
+pub struct ArrowRegen(pub u64);
+
 #[derive(Component, Serialize, Deserialize, Deref, Default, Debug, Clone, Copy)]
 pub struct HeadRotation(pub Quat);
 
@@ -261,6 +426,10 @@ pub enum PlayerAnimation {
     Swing,
     DrawBow,
     Eat,
+    /// Lowered body of a sneaking player whose hands are free
+    Sneak,
+    /// Forward lean of a sprinting player whose hands are free
+    Sprint,
 }
 
 #[derive(Bundle, Default)]
@@ -273,6 +442,11 @@ pub struct PlayerBundle {
     pub score: Score,
     pub breaking_status: BreakingStatusTracker,
     pub item_usage_status: ItemUsageStatusTracker,
+    pub bow_charge: BowCharge,
+    pub arrow_regen: ArrowRegen,
+    pub fall_speed: FallSpeed,
+    pub stance: PlayerStance,
+    pub attack_cooldown: AttackCooldown,
     pub transform: Transform,
     pub head_rotation: HeadRotation,
     pub animation: PlayerAnimation,
@@ -0,0 +1,157 @@
+use crate::config::MatchConfig;
+use crate::player::{PlayerActions, PlayerID};
+use crate::{GAME_VERSION, TickMessage, WorldSnapshot};
+use anyhow::{Context, bail};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+
+const REPLAY_MAGIC: &[u8; 4] = b"ADRP";
+pub const REPLAY_FORMAT_VERSION: u32 = 13;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplayPlayer {
+    pub id: PlayerID,
+    /// The address the player connected from
+    pub address: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplayHeader {
+    pub format_version: u32,
+    pub game_version: u32,
+    pub match_id: u64,
+    /// The seed the match was simulated with, see [`MatchConfig::seed`]
+    pub seed: u64,
+    pub players: [ReplayPlayer; 2],
+    pub config: MatchConfig,
+}
+
+impl ReplayHeader {
+    pub fn new(
+        match_id: u64,
+        seed: u64,
+        players: [ReplayPlayer; 2],
+        config: MatchConfig,
+    ) -> Self {
+        ReplayHeader {
+            format_version: REPLAY_FORMAT_VERSION,
+            game_version: GAME_VERSION,
+            match_id,
+            seed,
+            players,
+            config,
+        }
+    }
+}
+
+/// A replay is the header followed by the records in the order they happened.
+/// The first record is the snapshot of the world before the first tick.
+/// Every tick is followed by the actions the players answered it with, except for the last one.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum ReplayRecord {
+    Snapshot(WorldSnapshot),
+    Tick(TickMessage),
+    /// The actions used for the next tick, indexed by player ID
+    Actions([PlayerActions; 2]),
+}
+
+/// Writes a replay as length-prefixed postcard frames
+pub struct ReplayWriter<W: Write = BufWriter<File>> {
+    writer: W,
+}
+
+impl ReplayWriter {
+    pub fn create(path: &Path, header: &ReplayHeader) -> anyhow::Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create replay file {}", path.display()))?;
+        Self::new(BufWriter::new(file), header)
+    }
+}
+
+impl<W: Write> ReplayWriter<W> {
+    pub fn new(mut writer: W, header: &ReplayHeader) -> anyhow::Result<Self> {
+        writer.write_all(REPLAY_MAGIC)?;
+        write_frame(&mut writer, header)?;
+        Ok(ReplayWriter { writer })
+    }
+
+    pub fn write_record(&mut self, record: &ReplayRecord) -> anyhow::Result<()> {
+        write_frame(&mut self.writer, record)
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+fn write_frame<W: Write, T: Serialize>(writer: &mut W, value: &T) -> anyhow::Result<()> {
+    let bytes = postcard::to_allocvec(value)?;
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(&bytes)?;
+    Ok(())
+}
+
+/// Reads a replay written by [`ReplayWriter`], yielding the records after the header.
+/// A replay that was cut off (e.g. because the server crashed) ends at the last complete record.
+pub struct ReplayReader<R: Read = BufReader<File>> {
+    reader: R,
+    pub header: ReplayHeader,
+}
+
+impl ReplayReader {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open replay file {}", path.display()))?;
+        Self::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read> ReplayReader<R> {
+    pub fn new(mut reader: R) -> anyhow::Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != REPLAY_MAGIC {
+            bail!("Not a replay file");
+        }
+        let Some(header) = read_frame::<_, ReplayHeader>(&mut reader)? else {
+            bail!("Replay file has no header");
+        };
+        if header.format_version != REPLAY_FORMAT_VERSION {
+            bail!(
+                "Unsupported replay format version {} (expected {})",
+                header.format_version,
+                REPLAY_FORMAT_VERSION
+            );
+        }
+        Ok(ReplayReader { reader, header })
+    }
+}
+
+impl<R: Read> Iterator for ReplayReader<R> {
+    type Item = anyhow::Result<ReplayRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        read_frame(&mut self.reader).transpose()
+    }
+}
+
+fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> anyhow::Result<Option<T>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    // The length comes from the file, so the buffer only grows with the bytes actually read
+    let len = u32::from_le_bytes(len) as usize;
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < len {
+        return Ok(None);
+    }
+    Ok(Some(postcard::from_bytes(&bytes)?))
+}
@@ -0,0 +1,1322 @@
+use crate::config::{MatchConfig, TimeLimitOutcome};
+use crate::player::{
+    ArrowRegen, AttackCooldown, BowCharge, BreakingStatus, BreakingStatusTracker, FallSpeed,
+    HeadRotation, Health, HurtCooldown, Inventory, Item, ItemUsageStatus, ItemUsageStatusTracker,
+    PlayerActions, PlayerActionsTracker, PlayerAnimation, PlayerBundle, PlayerID, PlayerStance,
+    Score, ATTACK_KNOCKBACK, CRITICAL_HIT_MULTIPLIER, STRONG_ATTACK_CHARGE, PLAYER_HEIGHT,
+    PLAYER_INTERACT_RANGE, PLAYER_JUMP_SPEED, PLAYER_WIDTH, SPRINT_KNOCKBACK_MULTIPLIER,
+};
+use crate::world::{BlockType, ChunkMap, WorldPlugin};
+use crate::{AppState, Arrow, ArrowEvent, AutoDespawn, BlockBreaking, CollisionLayer, Damage, DamageSource, GameResults, Hit, PlayerInfo, TickMessage, WorldSnapshot, ARROW_GRAVITY_SCALE, ARROW_HEIGHT, ARROW_WIDTH, TICK_RATE};
+use avian3d::prelude::{
+    ActiveCollisionHooks, Collider, CollisionEventsEnabled, CollisionHooks, CollisionLayers,
+    CollisionStart, Collisions, Friction, GravityScale, LinearDamping, LinearVelocity, LockedAxes,
+    Restitution, RigidBody, SpatialQuery, SpatialQueryFilter, SweptCcd,
+};
+use avian3d::PhysicsPlugins;
+use bevy::ecs::schedule::ExecutorKind;
+use bevy::ecs::system::SystemParam;
+use bevy::platform::collections::HashMap;
+use bevy::prelude::*;
+use bevy::time::TimeUpdateStrategy;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+#[derive(EntityEvent)]
+struct GoalEvent(Entity);
+
+#[derive(EntityEvent)]
+struct DeathEvent(Entity);
+
+#[derive(Resource, Default)]
+struct Deaths(BTreeSet<PlayerID>);
+
+#[derive(Resource, Default)]
+struct Goals(Option<PlayerID>);
+
+#[derive(Resource, Default)]
+struct BlockUpdates(Vec<(IVec3, BlockType)>);
+
+#[derive(Resource, Default, Clone)]
+struct ArrowEvents(Vec<ArrowEvent>);
+
+/// Every time a player lost health this tick
+#[derive(Resource, Default)]
+struct Damages(Vec<Damage>);
+
+impl Damages {
+    /// Takes health from the player and records why
+    fn deal(&mut self, player_id: PlayerID, health: &mut Health, amount: f32, source: DamageSource) {
+        health.0 -= amount;
+        self.0.push(Damage {
+            player_id,
+            amount,
+            source,
+        });
+    }
+}
+
+/// The blocks players placed and who placed them, the rest of the blocks belong to the map
+#[derive(Resource, Default)]
+struct PlacedBlocks(HashMap<IVec3, PlayerID>);
+
+/// Melee hits that landed this tick
+#[derive(Resource, Default)]
+struct Hits(Vec<Hit>);
+
+#[derive(Resource, Default)]
+struct CurrentTick(u64);
+
+/// Set once the tick limit is reached with tied scores
+#[derive(Resource, Default)]
+struct SuddenDeath(bool);
+
+/// The only source of randomness in a match, seeded per match so it can be reproduced
+#[derive(Resource)]
+struct MatchRng(fastrand::Rng);
+
+/// A match simulated in-process without any networking, stepped one tick at a time with both players' actions.
+///
+/// The players spawn during the first step, so the actions passed to it have no effect.
+pub struct Match {
+    app: App,
+    tick: u64,
+    seed: u64,
+}
+
+impl Match {
+    /// Sets up a match with the given rules, seeded with `config.seed` or a random seed if it's unset.
+    /// Generated maps are generated with the same seed, see [`Match::config`] for the result.
+    pub fn new(mut config: MatchConfig) -> Self {
+        let seed = config.seed.unwrap_or_else(rand::random);
+        // Generators are checked when the config is loaded, and clamp what doesn't fit
+        config
+            .generate_map(seed)
+            .expect("Failed to generate the map");
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_plugins((
+                WorldPlugin::new(true),
+                PhysicsPlugins::new(PostUpdate)
+                    .with_collision_hooks::<ArrowHooks>()
+                    .build(),
+            ))
+            // Step by a fixed amount of game time per tick, however long the players take to answer
+            .insert_resource(TimeUpdateStrategy::ManualDuration(
+                Duration::from_secs(1) / TICK_RATE,
+            ))
+            .insert_resource(config)
+            .insert_resource(MatchRng(fastrand::Rng::with_seed(seed)))
+            .init_resource::<Deaths>()
+            .init_resource::<Goals>()
+            .init_resource::<BlockUpdates>()
+            .init_resource::<ArrowEvents>()
+            .init_resource::<Damages>()
+            .init_resource::<Hits>()
+            .init_resource::<PlacedBlocks>()
+            .init_resource::<CurrentTick>()
+            .init_resource::<SuddenDeath>()
+            .add_observer(update_score)
+            .add_observer(reset_players_after_goal)
+            .add_observer(reset_health_after_death)
+            .add_observer(reset_player_position_on_death)
+            .add_observer(reset_player_inv_on_death)
+            .add_observer(send_death_events)
+            .add_observer(send_goal_events)
+            .add_systems(Startup, setup)
+            .add_systems(PreUpdate, send_animations)
+            .add_systems(
+                Update,
+                (
+                    change_item_in_inv,
+                    apply_fall_damage.before(move_players),
+                    update_stance.before(move_players),
+                    move_players,
+                    place_block.after(change_item_in_inv).after(move_players),
+                    update_breaking_status
+                        .after(change_item_in_inv)
+                        .after(move_players),
+                    break_block.after(update_breaking_status),
+                    attack
+                        .after(change_item_in_inv)
+                        .after(move_players)
+                        .after(tick_hurt_cooldown),
+                    update_item_usage_status.after(change_item_in_inv),
+                    eat_golden_apple.after(update_item_usage_status),
+                    shoot_arrow.after(change_item_in_inv).after(move_players),
+                    regenerate_arrows.after(shoot_arrow),
+                    send_arrow_updates.after(shoot_arrow),
+                    send_arrow_despawns.after(send_arrow_updates),
+                    manage_arrows,
+                    tick_hurt_cooldown,
+                    check_goal.after(move_players),
+                    check_for_win.after(check_goal),
+                    check_time_limit.after(check_for_win),
+                    check_for_deaths,
+                    kill_oob_players.after(move_players),
+                ),
+            )
+            .add_systems(PostUpdate, update_info)
+            // Systems that aren't ordered relative to each other could otherwise run in a different order every tick
+            .edit_schedule(PreUpdate, run_single_threaded)
+            .edit_schedule(Update, run_single_threaded)
+            .edit_schedule(PostUpdate, run_single_threaded);
+
+        Match { app, tick: 0, seed }
+    }
+
+    /// Simulates the next tick with the players' actions (indexed by player ID) and returns its state
+    pub fn step(&mut self, actions: [PlayerActions; 2]) -> TickMessage {
+        let tick = self.tick;
+        let world = self.app.world_mut();
+        let mut query = world.query::<(&PlayerID, &mut PlayerActionsTracker)>();
+        for (player_id, mut actions_tracker) in query.iter_mut(world) {
+            actions_tracker.0 = actions[player_id.0 as usize];
+        }
+
+        // Reset per-tick resources
+        world.resource_mut::<Deaths>().0.clear();
+        world.resource_mut::<Goals>().0 = None;
+        world.resource_mut::<BlockUpdates>().0.clear();
+        world.resource_mut::<ArrowEvents>().0.clear();
+        world.resource_mut::<Damages>().0.clear();
+        world.resource_mut::<Hits>().0.clear();
+        world.resource_mut::<CurrentTick>().0 = tick;
+
+        self.app.update();
+        self.tick += 1;
+
+        let world = self.app.world_mut();
+        let tick_limit = world.resource::<MatchConfig>().tick_limit;
+        let sudden_death = world.resource::<SuddenDeath>().0;
+        TickMessage {
+            tick,
+            players: world
+                .query::<(&PlayerID, &PlayerInfo)>()
+                .iter_mut(world)
+                .sort_by::<(&PlayerID, &PlayerInfo)>(|(player_id1, _), (player_id2, _)| {
+                    player_id1.cmp(player_id2)
+                })
+                .map(|(_, info)| info.clone())
+                .collect::<Vec<_>>()
+                .try_into()
+                .unwrap(),
+            actions,
+            deaths: world.resource::<Deaths>().0.clone(),
+            goals: world.resource::<Goals>().0,
+            block_updates: world.resource::<BlockUpdates>().0.clone(),
+            arrow_events: world.resource::<ArrowEvents>().0.clone(),
+            damage: world.resource::<Damages>().0.clone(),
+            hits: world.resource::<Hits>().0.clone(),
+            game_results: world.get_resource::<GameResults>().map(|x| x.clone()),
+            ticks_remaining: (tick_limit > 0 && !sudden_death)
+                .then(|| tick_limit.saturating_sub(tick + 1)),
+            sudden_death,
+        }
+    }
+
+    /// Whether the match has been decided. The last tick returned by [`Match::step`] contains the results.
+    pub fn is_finished(&self) -> bool {
+        self.app.world().contains_resource::<GameResults>()
+    }
+
+    /// Ends the match with results decided outside the game (e.g. a forfeit).
+    /// They are reported by the next step, which is the last one.
+    pub fn finish(&mut self, results: GameResults) {
+        self.app.world_mut().insert_resource(results);
+    }
+
+    /// The number of ticks stepped so far
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn config(&self) -> &MatchConfig {
+        self.app.world().resource::<MatchConfig>()
+    }
+
+    /// The whole state of the match after the ticks stepped so far
+    pub fn snapshot(&mut self) -> WorldSnapshot {
+        let world = self.app.world_mut();
+        // Nothing has spawned before the first step, which leaves the initial state
+        let mut snapshot = WorldSnapshot::initial(world.resource::<MatchConfig>());
+        snapshot.tick = self.tick;
+        if let Ok(chunk_map) = world.query::<&ChunkMap>().single(world) {
+            snapshot.map = chunk_map.clone();
+        }
+        for (player_id, info, health, inventory, score) in world
+            .query::<(&PlayerID, &PlayerInfo, &Health, &Inventory, &Score)>()
+            .iter(world)
+        {
+            let i = player_id.0 as usize;
+            snapshot.players[i] = info.without_changes();
+            snapshot.health[i] = health.0;
+            snapshot.inventories[i] = inventory.clone();
+            snapshot.scores[i] = score.0;
+        }
+        for (entity, transform) in world
+            .query_filtered::<(Entity, &Transform), With<Arrow>>()
+            .iter(world)
+        {
+            snapshot
+                .arrows
+                .insert(entity.index_u32(), (transform.translation, transform.rotation));
+        }
+        snapshot
+    }
+
+    pub fn get_block(&mut self, pos: IVec3) -> BlockType {
+        let world = self.app.world_mut();
+        world
+            .query::<&ChunkMap>()
+            .single(world)
+            .map(|chunk_map| chunk_map.get_block(pos))
+            .unwrap_or_default()
+    }
+}
+
+fn run_single_threaded(schedule: &mut Schedule) {
+    schedule.set_executor_kind(ExecutorKind::SingleThreaded);
+}
+
+fn setup(mut commands: Commands, config: Res<MatchConfig>) {
+    commands.spawn((ChunkMap::from_map(&config.map), AutoDespawn(AppState::Game)));
+
+    for i in 0..2_i32 {
+        let spawn = config.map.spawn(PlayerID(i as u16));
+        commands.spawn((
+            PlayerBundle {
+                id: PlayerID(i as u16),
+                health: Health(config.max_health),
+                inventory: Inventory::new(config.starting_kit.clone()),
+                transform: Transform::from_translation(spawn.translation()),
+                head_rotation: HeadRotation(Quat::from_rotation_y(spawn.yaw)),
+                ..default()
+            },
+            PlayerInfo::default(),
+            RigidBody::Dynamic,
+            Collider::cuboid(PLAYER_WIDTH, PLAYER_HEIGHT, PLAYER_WIDTH),
+            CollisionLayers::new(
+                CollisionLayer::Player,
+                [CollisionLayer::World, CollisionLayer::Projectile],
+            ),
+            SweptCcd::default(),
+            LockedAxes::ROTATION_LOCKED,
+            Friction::new(0.0),
+            Restitution::new(0.0),
+            LinearDamping(2.0),
+            GravityScale(3.0),
+        ));
+    }
+}
+
+fn update_info(
+    mut player_query: Query<(
+        &mut PlayerInfo,
+        &Transform,
+        &HeadRotation,
+        &LinearVelocity,
+        Ref<Health>,
+        Ref<Inventory>,
+        &PlayerAnimation,
+        Ref<HurtCooldown>,
+        &BowCharge,
+        &BreakingStatusTracker,
+        &PlayerStance,
+        &AttackCooldown,
+    )>,
+    config: Res<MatchConfig>,
+) {
+    for (
+        mut info,
+        transform,
+        head_rotation,
+        vel,
+        health,
+        inv,
+        animation,
+        hurt_cooldown,
+        bow_charge,
+        breaking_status_tracker,
+        stance,
+        attack_cooldown,
+    ) in player_query.iter_mut()
+    {
+        info.position = transform.translation;
+        info.yaw = head_rotation.0.to_euler(EulerRot::YXZ).0;
+        info.pitch = -head_rotation.0.to_euler(EulerRot::YXZ).2;
+        info.velocity = vel.0;
+        info.health_update = if health.is_changed() {
+            Some(health.0)
+        } else {
+            None
+        };
+        info.inventory_update = if inv.is_changed() {
+            Some(inv.clone())
+        } else {
+            None
+        };
+        info.animation = *animation;
+        info.hurt_update = if hurt_cooldown.is_changed() {
+            Some(hurt_cooldown.0 > 0)
+        } else {
+            None
+        };
+        info.bow_charge = bow_charge.level(config.bow_charge_ticks);
+        info.stance = *stance;
+        info.attack_charge = attack_cooldown.charge(inv.get_selected_item());
+        info.breaking = breaking_status_tracker
+            .0
+            .as_ref()
+            .map(|breaking_status| BlockBreaking {
+                block_pos: breaking_status.block_pos,
+                progress: (breaking_status.progress as f32 / breaking_status.hardness.max(1) as f32)
+                    .min(1.0),
+            });
+    }
+}
+
+fn change_item_in_inv(mut player_query: Query<(&PlayerActionsTracker, &mut Inventory)>) {
+    for (actions, mut inventory) in player_query.iter_mut() {
+        if let Some(item) = actions.0.item_change {
+            inventory.select_item(item);
+        }
+    }
+}
+
+/// Picks the players' stances from their actions and fits their hitboxes to them.
+/// Players keep sneaking while there's no room above them to stand up.
+fn update_stance(
+    mut player_query: Query<(
+        &PlayerActionsTracker,
+        &Transform,
+        &mut PlayerStance,
+        &mut Collider,
+    )>,
+    chunk_map: Single<&ChunkMap>,
+) {
+    for (actions, transform, mut stance, mut collider) in player_query.iter_mut() {
+        let mut new_stance = PlayerStance::from_actions(&actions.0);
+        let feet = transform.translation - Vec3::Y * PLAYER_HEIGHT / 2.0;
+        if new_stance.height() > stance.height()
+            && !has_headroom(&chunk_map, feet, stance.height(), new_stance.height())
+        {
+            new_stance = *stance;
+        }
+        if new_stance.height() != stance.height() {
+            *collider = player_collider(new_stance);
+        }
+        stance.set_if_neq(new_stance);
+    }
+}
+
+/// The hitbox of a player in the stance, lowered hitboxes keep their bottom at the player's feet
+fn player_collider(stance: PlayerStance) -> Collider {
+    let height = stance.height();
+    if height == PLAYER_HEIGHT {
+        return Collider::cuboid(PLAYER_WIDTH, PLAYER_HEIGHT, PLAYER_WIDTH);
+    }
+    Collider::compound(vec![(
+        Vec3::Y * (height - PLAYER_HEIGHT) / 2.0,
+        Quat::IDENTITY,
+        Collider::cuboid(PLAYER_WIDTH, height, PLAYER_WIDTH),
+    )])
+}
+
+/// Whether nothing is in the way of a player with their feet at this position growing from one hitbox height to another
+fn has_headroom(chunk_map: &ChunkMap, feet: Vec3, from: f32, to: f32) -> bool {
+    let half_width = PLAYER_WIDTH / 2.0 - 0.001;
+    let min = (feet + Vec3::new(-half_width, from, -half_width)).floor().as_ivec3();
+    let max = (feet + Vec3::new(half_width, to - 0.001, half_width)).floor().as_ivec3();
+    (min.x..=max.x).all(|x| {
+        (min.y..=max.y).all(|y| {
+            (min.z..=max.z).all(|z| chunk_map.get_block(IVec3::new(x, y, z)) == BlockType::Air)
+        })
+    })
+}
+
+/// Whether a block under a player with their feet at this position would hold them up
+fn has_support(chunk_map: &ChunkMap, feet: Vec3) -> bool {
+    // Players can stand with their hitbox barely overlapping a block
+    let half_width = PLAYER_WIDTH / 2.0 - 0.001;
+    let min = (feet.xz() - half_width).floor().as_ivec2();
+    let max = (feet.xz() + half_width).floor().as_ivec2();
+    let y = (feet.y - 0.1).floor() as i32;
+    (min.x..=max.x).any(|x| {
+        (min.y..=max.y).any(|z| chunk_map.get_block(IVec3::new(x, y, z)) != BlockType::Air)
+    })
+}
+
+fn move_players(
+    mut player_query: Query<(
+        Entity,
+        &PlayerID,
+        &PlayerActionsTracker,
+        &PlayerStance,
+        &Transform,
+        &mut HeadRotation,
+        &mut LinearVelocity,
+    )>,
+    collisions: Collisions,
+    chunk_map: Single<&ChunkMap>,
+) {
+    for (entity, player_id, actions, stance, transform, mut rotation, mut vel) in
+        player_query.iter_mut()
+    {
+        let mut dir = Vec3::ZERO;
+        if actions.0.is_set(PlayerActions::MOVE_FORWARD) {
+            dir.x += 1.0;
+        }
+        if actions.0.is_set(PlayerActions::MOVE_BACKWARD) {
+            dir.x -= 1.0;
+        }
+        if actions.0.is_set(PlayerActions::MOVE_LEFT) {
+            dir.z += 1.0;
+        }
+        if actions.0.is_set(PlayerActions::MOVE_RIGHT) {
+            dir.z -= 1.0;
+        }
+
+        rotation.0 = actions.0.rotation.to_quat(*player_id);
+
+        let on_ground = is_on_ground(entity, &collisions);
+        let jump = actions.0.is_set(PlayerActions::JUMP) && on_ground;
+        let speed = if jump {
+            stance.speed() * 2.0
+        } else {
+            stance.speed()
+        };
+        let mut delta = (rotation.0 * dir * speed) - vel.0;
+        if !on_ground {
+            delta *= 0.01;
+        }
+
+        delta.y = if jump {
+            PLAYER_JUMP_SPEED - vel.0.y
+        } else {
+            0.0
+        };
+
+        vel.0 += delta;
+
+        // Sneaking players stop at the edge of the block they stand on, one axis at a time so they can slide along it
+        if *stance == PlayerStance::Sneaking && on_ground && !jump {
+            let feet = transform.translation - Vec3::Y * PLAYER_HEIGHT / 2.0;
+            let step = vel.0 / TICK_RATE as f32;
+            if !has_support(&chunk_map, feet + Vec3::X * step.x) {
+                vel.0.x = 0.0;
+            }
+            if !has_support(&chunk_map, feet + Vec3::new(vel.0.x / TICK_RATE as f32, 0.0, step.z)) {
+                vel.0.z = 0.0;
+            }
+        }
+    }
+}
+
+/// Whether the player is standing on something, going by the contacts of the last physics step
+fn is_on_ground(entity: Entity, collisions: &Collisions) -> bool {
+    collisions
+        .collisions_with(entity)
+        .any(|contact_pair| contact_pair.total_normal_impulse().y > 0.1)
+}
+
+/// Hurts players that land faster than the safe speed
+fn apply_fall_damage(
+    mut player_query: Query<(
+        Entity,
+        &PlayerID,
+        &LinearVelocity,
+        &mut FallSpeed,
+        &mut Health,
+    )>,
+    collisions: Collisions,
+    config: Res<MatchConfig>,
+    mut damages: ResMut<Damages>,
+) {
+    for (entity, player_id, vel, mut fall_speed, mut health) in player_query.iter_mut() {
+        if !is_on_ground(entity, &collisions) {
+            fall_speed.0 = fall_speed.0.max(-vel.0.y);
+            continue;
+        }
+        // The landing already stopped the player, so the speed is the one from their last tick in the air
+        let damage = (fall_speed.0 - config.fall_damage_safe_speed) * config.fall_damage_per_speed;
+        fall_speed.0 = 0.0;
+        if damage > 0.0 {
+            damages.deal(*player_id, &mut health, damage, DamageSource::Fall);
+        }
+    }
+}
+
+/// Finds the block a player looking from their eyes in the given direction would place against or dig, and the face that was hit
+pub fn raycast_for_block(
+    origin: Vec3,
+    player_rot: Quat,
+    chunk_map: &ChunkMap,
+) -> Option<(IVec3, IVec3)> {
+    let mut pos = origin;
+    let dir_inv = 1.0 / (player_rot * Vec3::X).normalize();
+
+    let step = dir_inv.map(|a| a.signum());
+    let select = dir_inv.map(|a| 0.5 + 0.5 * a.signum());
+
+    loop {
+        let floored_pos = pos.floor();
+        let floored_pos_ivec3 = pos.floor().as_ivec3();
+        if chunk_map.get_block(floored_pos_ivec3) != BlockType::Air {
+            let t1 = (floored_pos - origin) * dir_inv;
+            let t2 = (floored_pos + Vec3::splat(1.0) - origin) * dir_inv;
+            let t_min = t1.min(t2);
+            let t_hit = t_min.x.max(t_min.y).max(t_min.z);
+
+            let face = (if t_hit == t_min.x {
+                Vec3::new(-step.x, 0.0, 0.0)
+            } else if t_hit == t_min.y {
+                Vec3::new(0.0, -step.y, 0.0)
+            } else {
+                Vec3::new(0.0, 0.0, -step.z)
+            })
+            .normalize()
+            .ceil()
+            .as_ivec3();
+
+            return Some((floored_pos_ivec3, face));
+        } else if (pos - origin).length_squared() > (PLAYER_INTERACT_RANGE * PLAYER_INTERACT_RANGE) {
+            return None;
+        }
+
+        let planes = pos.floor() + select;
+        let t = (planes - origin) * dir_inv;
+
+        if t.x < t.y {
+            if t.x < t.z {
+                pos.x += step.x;
+            } else {
+                pos.z += step.z;
+            }
+        } else {
+            if t.y < t.z {
+                pos.y += step.y;
+            } else {
+                pos.z += step.z;
+            }
+        }
+    }
+}
+
+fn place_block(
+    mut player_query: Query<(
+        Entity,
+        &PlayerID,
+        &PlayerActionsTracker,
+        &PlayerStance,
+        &mut Inventory,
+        &HeadRotation,
+        &Transform,
+    )>,
+    mut chunk_map: Single<&mut ChunkMap>,
+    mut block_updates: ResMut<BlockUpdates>,
+    mut placed_blocks: ResMut<PlacedBlocks>,
+    config: Res<MatchConfig>,
+) {
+    let mut placements = Vec::new();
+    for (entity, player_id, actions, stance, inv, rotation, transform) in player_query.iter_mut() {
+        if actions.0.is_set(PlayerActions::PLACE_BLOCK) {
+            if inv.get_selected_item() == Item::Block && inv.get_count(Item::Block) > 0 {
+                let Some((block_pos, face)) = raycast_for_block(
+                    stance.eye_position(transform.translation),
+                    rotation.0,
+                    &chunk_map,
+                ) else {
+                    continue;
+                };
+
+                let block_pos = block_pos + face;
+                if !config.map.build_limits.contains(block_pos) {
+                    continue;
+                }
+                placements.push((entity, *player_id, block_pos));
+            }
+        }
+    }
+    'outer: for (entity, player_id, block_pos) in placements {
+        for (_, _, _, _, _, _, transform) in player_query.iter() {
+            let foot_pos = (transform.translation - Vec3::ZERO.with_y(PLAYER_HEIGHT / 2.0)).floor().as_ivec3() + IVec3::Y;
+            if block_pos == foot_pos || block_pos == foot_pos + IVec3::Y {
+                continue 'outer;
+            }
+        }
+
+        let block_type = BlockType::team(player_id);
+        chunk_map.set_block(block_pos, block_type).unwrap();
+        block_updates.0.push((block_pos, block_type));
+        placed_blocks.0.insert(block_pos, player_id);
+
+        player_query.get_mut(entity).unwrap().4.remove_item(Item::Block, 1);
+    }
+}
+
+/// Advances the breaking of the block the player is digging at, which starts over when they stop or look elsewhere
+fn update_breaking_status(
+    mut player_query: Query<(
+        &PlayerID,
+        &PlayerActionsTracker,
+        &Inventory,
+        &mut BreakingStatusTracker,
+        &PlayerStance,
+        &HeadRotation,
+        &Transform,
+    )>,
+    chunk_map: Single<&ChunkMap>,
+    placed_blocks: Res<PlacedBlocks>,
+    config: Res<MatchConfig>,
+) {
+    for (player_id, actions, inv, mut breaking_status_tracker, stance, rotation, transform) in
+        player_query.iter_mut()
+    {
+        let target = actions
+            .0
+            .is_set(PlayerActions::DIG_BLOCK)
+            .then(|| {
+                raycast_for_block(
+                    stance.eye_position(transform.translation),
+                    rotation.0,
+                    &chunk_map,
+                )
+            })
+            .flatten()
+            .and_then(|(block_pos, _)| {
+                let hardness = config.break_ticks(
+                    chunk_map.get_block(block_pos),
+                    placed_blocks.0.get(&block_pos).copied(),
+                    *player_id,
+                )?;
+                Some((block_pos, hardness))
+            });
+        let Some((block_pos, hardness)) = target else {
+            if breaking_status_tracker.0.is_some() {
+                breaking_status_tracker.0 = None;
+            }
+            continue;
+        };
+
+        let speed = config.tool_speed(inv.get_selected_item());
+        match breaking_status_tracker.0.as_mut() {
+            Some(breaking_status) if breaking_status.block_pos == block_pos => {
+                breaking_status.progress += speed;
+            }
+            _ => {
+                breaking_status_tracker.0 = Some(BreakingStatus {
+                    block_pos,
+                    progress: speed,
+                    hardness,
+                });
+            }
+        }
+    }
+}
+
+fn break_block(
+    mut player_query: Query<&mut BreakingStatusTracker, Changed<BreakingStatusTracker>>,
+    mut chunk_map: Single<&mut ChunkMap>,
+    mut block_updates: ResMut<BlockUpdates>,
+    mut placed_blocks: ResMut<PlacedBlocks>,
+) {
+    for mut breaking_status_tracker in player_query.iter_mut() {
+        let Some(breaking_status) = breaking_status_tracker.0.as_ref() else {
+            continue;
+        };
+        if breaking_status.progress < breaking_status.hardness {
+            continue;
+        }
+        let block_pos = breaking_status.block_pos;
+        // Both players can finish breaking the same block in a tick
+        if chunk_map.get_block(block_pos) != BlockType::Air {
+            chunk_map.set_block(block_pos, BlockType::Air).unwrap();
+            block_updates.0.push((block_pos, BlockType::Air));
+            placed_blocks.0.remove(&block_pos);
+        }
+        breaking_status_tracker.0 = None;
+    }
+}
+
+/// Swings the held item of attacking players, hitting anyone within reach.
+/// Damage scales with the attack charge, falling players land critical hits and sprinting adds knockback.
+fn attack(
+    mut player_query: Query<(
+        Entity,
+        &PlayerID,
+        &PlayerStance,
+        &PlayerActionsTracker,
+        &Inventory,
+        &HeadRotation,
+        &Transform,
+        &mut AttackCooldown,
+    )>,
+    mut player_query_2: Query<(&PlayerID, &mut Health, &mut HurtCooldown, &mut LinearVelocity)>,
+    spatial_query: SpatialQuery,
+    collisions: Collisions,
+    config: Res<MatchConfig>,
+    mut rng: ResMut<MatchRng>,
+    mut damages: ResMut<Damages>,
+    mut hits: ResMut<Hits>,
+) {
+    // Target, attacker, attacker ID, damage, knockback, critical and whether the sprint bonus is used up
+    let mut hit_queue: Vec<(Entity, Entity, PlayerID, f32, Vec3, bool, bool)> = Vec::new();
+    for (entity, attacker_id, stance, actions, inv, rotation, transform, mut cooldown) in
+        player_query.iter_mut()
+    {
+        if *stance != PlayerStance::Sprinting {
+            cooldown.sprint_hit = false;
+        }
+        if !actions.0.is_set(PlayerActions::ATTACK) {
+            cooldown.ticks_since_attack = cooldown.ticks_since_attack.saturating_add(1);
+            continue;
+        }
+        let item = inv.get_selected_item();
+        let charge = cooldown.charge(item);
+        cooldown.ticks_since_attack = 0;
+
+        let origin = stance.eye_position(transform.translation);
+        let dir = rotation.0 * Vec3::X;
+
+        let (_, _, _, vel) = player_query_2.get(entity).unwrap();
+        let strong = charge >= STRONG_ATTACK_CHARGE;
+        let critical = strong && vel.0.y < 0.0 && !is_on_ground(entity, &collisions);
+        let mut damage = item.damage() * (0.2 + 0.8 * charge * charge);
+        if critical {
+            damage *= CRITICAL_HIT_MULTIPLIER;
+        }
+        let sprint_hit = strong && *stance == PlayerStance::Sprinting && !cooldown.sprint_hit;
+        let mut knockback = ATTACK_KNOCKBACK;
+        if sprint_hit {
+            knockback *= SPRINT_KNOCKBACK_MULTIPLIER;
+        }
+
+        let ray_hits = spatial_query.ray_hits(
+            origin,
+            Dir3::new(dir).unwrap(),
+            PLAYER_INTERACT_RANGE,
+            10,
+            true,
+            &SpatialQueryFilter::default(),
+        );
+        for hit in ray_hits.iter() {
+            if hit.entity == entity {
+                continue;
+            }
+            hit_queue.push((
+                hit.entity,
+                entity,
+                *attacker_id,
+                damage,
+                Vec3::new(dir.x, 0.5, dir.z).normalize() * knockback,
+                critical,
+                sprint_hit,
+            ));
+        }
+    }
+    rng.0.shuffle(hit_queue.as_mut_slice());
+    for (entity, attacker, attacker_id, damage, knockback, critical, sprint_hit) in hit_queue {
+        if let Ok((player_id, mut health, mut hurt_cooldown, mut vel)) =
+            player_query_2.get_mut(entity)
+        {
+            if hurt_cooldown.0 > 0 {
+                continue;
+            }
+            damages.deal(*player_id, &mut health, damage, DamageSource::Melee(attacker_id));
+            hurt_cooldown.start(config.hurt_cooldown_ticks);
+            vel.0 += knockback;
+            // Swings that miss or hit a player on hurt cooldown keep the bonus
+            if sprint_hit && let Ok((.., mut cooldown)) = player_query.get_mut(attacker) {
+                cooldown.sprint_hit = true;
+            }
+            hits.0.push(Hit {
+                attacker: attacker_id,
+                target: *player_id,
+                damage,
+                knockback,
+                critical,
+            });
+        }
+    }
+}
+
+fn update_item_usage_status(
+    mut player_query: Query<(
+        &PlayerActionsTracker,
+        &mut ItemUsageStatusTracker,
+        &Inventory,
+    )>,
+) {
+    for (actions, mut item_usage_tracker, inv) in player_query.iter_mut() {
+        if let Some(item_usage) = item_usage_tracker.0.as_mut() {
+            if actions.0.is_set(PlayerActions::USE_ITEM)
+                && inv.get_count(inv.get_selected_item()) > 0
+            {
+                if inv.get_selected_item() == item_usage.item {
+                    if let Some(ticks_left) = item_usage.ticks_left.checked_sub(1) {
+                        item_usage.ticks_left = ticks_left;
+                    } else {
+                        item_usage_tracker.0 = None;
+                    }
+                } else {
+                    item_usage_tracker.0 = Some(ItemUsageStatus::new(inv.get_selected_item()));
+                }
+            } else {
+                item_usage_tracker.0 = None;
+            }
+        } else {
+            if actions.0.is_set(PlayerActions::USE_ITEM)
+                && inv.get_count(inv.get_selected_item()) > 0
+            {
+                item_usage_tracker.0 = Some(ItemUsageStatus::new(inv.get_selected_item()));
+            }
+        }
+    }
+}
+
+fn eat_golden_apple(
+    mut player_query: Query<(&ItemUsageStatusTracker, &mut Health, &mut Inventory)>,
+    config: Res<MatchConfig>,
+) {
+    for (item_usage_tracker, mut health, mut inv) in player_query.iter_mut() {
+        let Some(item_usage) = item_usage_tracker.0.as_ref() else {
+            continue;
+        };
+        if item_usage.item != Item::GoldenApple || item_usage.ticks_left > 0 {
+            continue;
+        }
+        health.0 = config.max_health;
+        inv.remove_item(Item::GoldenApple, 1);
+    }
+}
+
+/// Shots weaker than this are dropped without firing the arrow
+const MIN_BOW_POWER: f32 = 0.1;
+
+/// Draws the bow while the player uses it with arrows left, and shoots once they let go
+fn shoot_arrow(
+    mut player_query: Query<(
+        &PlayerID,
+        &PlayerActionsTracker,
+        &PlayerStance,
+        &mut BowCharge,
+        &mut Inventory,
+        &HeadRotation,
+        &Transform,
+    )>,
+    config: Res<MatchConfig>,
+    mut commands: Commands,
+) {
+    for (player_id, actions, stance, mut bow_charge, mut inv, rotation, transform) in
+        player_query.iter_mut()
+    {
+        let can_shoot = inv.get_selected_item() == Item::Bow
+            && inv.get_count(Item::Bow) > 0
+            && inv.get_count(Item::Arrow) > 0;
+        if can_shoot && actions.0.is_set(PlayerActions::USE_ITEM) {
+            bow_charge.0 += 1;
+            continue;
+        }
+        if bow_charge.0 == 0 {
+            continue;
+        }
+        let power = bow_charge.power(config.bow_charge_ticks);
+        bow_charge.0 = 0;
+        // Switching away from the bow puts the arrow back
+        if !can_shoot || power < MIN_BOW_POWER {
+            continue;
+        }
+        inv.remove_item(Item::Arrow, 1);
+
+        let dir = rotation.0 * Vec3::X.normalize();
+        let origin = stance.eye_position(transform.translation) + dir;
+
+        commands
+            .spawn((
+                Arrow {
+                    shooter: *player_id,
+                    damage: config.arrow_damage * power,
+                    ..default()
+                },
+                RigidBody::Dynamic,
+                Collider::cuboid(ARROW_WIDTH, ARROW_HEIGHT, ARROW_WIDTH),
+                CollisionLayers::new(
+                    CollisionLayer::Projectile,
+                    [CollisionLayer::World, CollisionLayer::Player],
+                ),
+                CollisionEventsEnabled,
+                ActiveCollisionHooks::FILTER_PAIRS,
+                SweptCcd::default(),
+                LockedAxes::ROTATION_LOCKED,
+                Transform::from_translation(origin),
+                LinearVelocity(dir * config.arrow_speed * power),
+                Friction::new(100.0),
+                Restitution::new(0.0),
+                GravityScale(ARROW_GRAVITY_SCALE),
+            ))
+            .observe(handle_arrow_collision);
+    }
+}
+
+#[derive(SystemParam)]
+pub struct ArrowHooks<'w, 's> {
+    arrow_query: Query<'w, 's, &'static Arrow>,
+}
+
+impl CollisionHooks for ArrowHooks<'_, '_> {
+    fn filter_pairs(&self, collider1: Entity, collider2: Entity, _commands: &mut Commands) -> bool {
+        if let Ok(arrow) = self.arrow_query.get(collider1) {
+            if arrow.ticks_in_ground > 0 {
+                return false;
+            }
+        }
+        if let Ok(arrow) = self.arrow_query.get(collider2) {
+            if arrow.ticks_in_ground > 0 {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+fn handle_arrow_collision(
+    event: On<CollisionStart>,
+    arrow_query: Query<(&Arrow, &LinearVelocity)>,
+    mut player_query: Query<
+        (&PlayerID, &mut Health, &mut HurtCooldown, &mut LinearVelocity),
+        Without<Arrow>,
+    >,
+    mut commands: Commands,
+    config: Res<MatchConfig>,
+    mut damages: ResMut<Damages>,
+) {
+    let Ok((arrow, arrow_vel)) = arrow_query.get(event.collider1) else {
+        return;
+    };
+    if arrow.ticks_in_ground > 0 {
+        return;
+    }
+    let Ok((player_id, mut health, mut hurt_cooldown, mut player_vel)) =
+        player_query.get_mut(event.collider2)
+    else {
+        return;
+    };
+    commands.entity(event.collider1).despawn();
+    damages.deal(
+        *player_id,
+        &mut health,
+        arrow.damage,
+        DamageSource::Arrow(arrow.shooter),
+    );
+    hurt_cooldown.start(config.hurt_cooldown_ticks);
+    player_vel.0 += arrow_vel.0.normalize() * 10.0;
+}
+
+/// Gives players back one arrow every `arrow_regen_ticks` until they have as many as they started with
+fn regenerate_arrows(
+    mut player_query: Query<(&mut ArrowRegen, &mut Inventory)>,
+    config: Res<MatchConfig>,
+) {
+    if config.arrow_regen_ticks == 0 {
+        return;
+    }
+    let max_arrows = config.starting_kit.get(&Item::Arrow).copied().unwrap_or(0);
+    for (mut regen, mut inv) in player_query.iter_mut() {
+        if inv.get_count(Item::Arrow) >= max_arrows {
+            regen.0 = 0;
+            continue;
+        }
+        regen.0 += 1;
+        if regen.0 >= config.arrow_regen_ticks {
+            inv.add_item(Item::Arrow, 1);
+            regen.0 = 0;
+        }
+    }
+}
+
+fn manage_arrows(
+    mut arrow_query: Query<(Entity, &mut Arrow, &Transform, &LinearVelocity)>,
+    mut commands: Commands,
+    config: Res<MatchConfig>,
+) {
+    for (entity, mut arrow, transform, vel) in arrow_query.iter_mut() {
+        if transform.translation.y < config.map.kill_plane {
+            commands.entity(entity).despawn();
+        }
+        if vel.length() < 0.1 {
+            arrow.ticks_in_ground += 1;
+            if arrow.ticks_in_ground > config.arrow_ground_ticks {
+                commands.entity(entity).despawn();
+            }
+        } else {
+            arrow.ticks_in_ground = 0;
+        }
+    }
+}
+
+fn tick_hurt_cooldown(mut player_query: Query<&mut HurtCooldown>) {
+    for mut hurt_cooldown in player_query.iter_mut() {
+        hurt_cooldown.0 = hurt_cooldown.0.saturating_sub(1);
+    }
+}
+
+/// Check if any player has reached their goal area
+/// Only one player can score at a time; if multiple are in the goal area, one is chosen at random
+fn check_goal(
+    player_query: Query<(Entity, &PlayerID, &Transform)>,
+    config: Res<MatchConfig>,
+    mut rng: ResMut<MatchRng>,
+    mut commands: Commands,
+) {
+    let mut entities = Vec::new();
+    for (entity, player_id, transform) in player_query.iter() {
+        let pos = transform.translation.floor().as_ivec3();
+        if config.map.goal(*player_id).contains(pos) {
+            entities.push(entity);
+        }
+        // Kill the player if they are in their own goal, without scoring
+        if config.map.goal(PlayerID(player_id.0 ^ 1)).contains(pos) {
+            commands.trigger(DeathEvent(entity));
+        }
+    }
+    if let Some(chosen_entity) = entities.get(rng.0.usize(..entities.len().max(1))) {
+        commands.trigger(GoalEvent(*chosen_entity));
+    };
+}
+
+fn update_score(event: On<GoalEvent>, mut player_query: Query<&mut Score>) {
+    let Ok(mut score) = player_query.get_mut(event.0) else {
+        return;
+    };
+    score.0 += 1;
+}
+
+fn check_for_win(
+    player_query: Query<(&PlayerID, &Score), Changed<Score>>,
+    mut commands: Commands,
+    config: Res<MatchConfig>,
+    sudden_death: Res<SuddenDeath>,
+    game_results: Option<Res<GameResults>>,
+) {
+    // Don't overwrite a result that was decided outside the game (e.g. a forfeit)
+    if game_results.is_some() {
+        return;
+    }
+    for (player_id, score) in player_query.iter() {
+        if score.0 >= config.score_to_win || sudden_death.0 {
+            commands.insert_resource(GameResults {
+                winner: Some(player_id.0),
+                reason: String::new(),
+            });
+        }
+    }
+}
+
+/// Decide the match once the tick limit is reached (the last tick is `tick_limit - 1`)
+fn check_time_limit(
+    player_query: Query<(&PlayerID, &Score)>,
+    current_tick: Res<CurrentTick>,
+    mut sudden_death: ResMut<SuddenDeath>,
+    config: Res<MatchConfig>,
+    game_results: Option<Res<GameResults>>,
+    mut commands: Commands,
+) {
+    if config.tick_limit == 0
+        || current_tick.0 + 1 < config.tick_limit
+        || sudden_death.0
+        || game_results.is_some()
+    {
+        return;
+    }
+
+    let mut scores = [0; 2];
+    for (player_id, score) in player_query.iter() {
+        scores[player_id.0 as usize] = score.0;
+    }
+    let leader = match scores[0].cmp(&scores[1]) {
+        Ordering::Greater => Some(0),
+        Ordering::Less => Some(1),
+        Ordering::Equal => None,
+    };
+
+    match (config.time_limit_outcome, leader) {
+        (TimeLimitOutcome::Draw, _) | (TimeLimitOutcome::HigherScore, None) => {
+            commands.insert_resource(GameResults {
+                winner: None,
+                reason: "Draw (time limit reached)".to_string(),
+            });
+        }
+        (_, Some(winner)) => {
+            commands.insert_resource(GameResults {
+                winner: Some(winner),
+                reason: "Time limit reached".to_string(),
+            });
+        }
+        (TimeLimitOutcome::SuddenDeath, None) => {
+            info!("Time limit reached with tied scores, starting sudden death");
+            sudden_death.0 = true;
+        }
+    }
+}
+
+// Use DeathEvent to reset players after a goal is scored
+fn reset_players_after_goal(
+    _: On<GoalEvent>,
+    mut player_query: Query<Entity, With<PlayerID>>,
+    mut commands: Commands,
+) {
+    for entity in player_query.iter_mut() {
+        commands.trigger(DeathEvent(entity));
+    }
+}
+
+fn check_for_deaths(player_query: Query<(Entity, &Health)>, mut commands: Commands) {
+    for (entity, health) in player_query.iter() {
+        if health.0 <= 0.0 {
+            commands.trigger(DeathEvent(entity));
+        }
+    }
+}
+
+fn reset_health_after_death(
+    event: On<DeathEvent>,
+    mut player_query: Query<&mut Health>,
+    config: Res<MatchConfig>,
+) {
+    let Ok(mut health) = player_query.get_mut(event.0) else {
+        return;
+    };
+    health.0 = config.max_health;
+}
+
+fn reset_player_position_on_death(
+    event: On<DeathEvent>,
+    mut player_query: Query<(
+        &PlayerID,
+        &mut Transform,
+        &mut HeadRotation,
+        &mut LinearVelocity,
+        &mut FallSpeed,
+    )>,
+    config: Res<MatchConfig>,
+) {
+    let (player_id, mut transform, mut rotation, mut vel, mut fall_speed) =
+        player_query.get_mut(event.0).unwrap();
+    let spawn = config.map.spawn(*player_id);
+    transform.translation = spawn.translation();
+    rotation.0 = Quat::from_rotation_y(spawn.yaw);
+    vel.0 = Vec3::ZERO;
+    // Falling into the void shouldn't hurt again on the respawn landing
+    fall_speed.0 = 0.0;
+}
+
+fn reset_player_inv_on_death(
+    event: On<DeathEvent>,
+    mut player_query: Query<(&mut Inventory, &mut BowCharge, &mut ArrowRegen)>,
+    config: Res<MatchConfig>,
+) {
+    let Ok((mut inventory, mut bow_charge, mut arrow_regen)) = player_query.get_mut(event.0) else {
+        return;
+    };
+    *inventory = Inventory::new(config.starting_kit.clone());
+    bow_charge.0 = 0;
+    arrow_regen.0 = 0;
+}
+
+fn kill_oob_players(
+    mut player_query: Query<(&PlayerID, &mut Health, &Transform)>,
+    config: Res<MatchConfig>,
+    mut damages: ResMut<Damages>,
+) {
+    for (player_id, mut health, transform) in player_query.iter_mut() {
+        if transform.translation.y < config.map.kill_plane && health.0 > 0.0 {
+            let remaining = health.0;
+            damages.deal(*player_id, &mut health, remaining, DamageSource::Void);
+        }
+    }
+}
+
+fn send_animations(
+    mut player_query: Query<(
+        &PlayerActionsTracker,
+        &Inventory,
+        &PlayerStance,
+        &mut PlayerAnimation,
+    )>,
+) {
+    for (actions, inv, stance, mut animation) in player_query.iter_mut() {
+        if actions.0.is_set(PlayerActions::ATTACK)
+            || actions.0.is_set(PlayerActions::DIG_BLOCK)
+            || (actions.0.is_set(PlayerActions::PLACE_BLOCK)
+                && inv.get_selected_item() == Item::Block
+                && inv.get_count(Item::Block) > 0)
+        {
+            *animation = PlayerAnimation::Swing;
+        } else if actions.0.is_set(PlayerActions::USE_ITEM)
+            && inv.get_count(inv.get_selected_item()) > 0
+        {
+            match inv.get_selected_item() {
+                Item::Bow if inv.get_count(Item::Arrow) > 0 => {
+                    *animation = PlayerAnimation::DrawBow;
+                }
+                Item::GoldenApple => {
+                    *animation = PlayerAnimation::Eat;
+                }
+                _ => {}
+            }
+        } else {
+            *animation = match stance {
+                PlayerStance::Standing => PlayerAnimation::None,
+                PlayerStance::Sprinting => PlayerAnimation::Sprint,
+                PlayerStance::Sneaking => PlayerAnimation::Sneak,
+            };
+        }
+    }
+}
+
+fn send_death_events(
+    event: On<DeathEvent>,
+    player_query: Query<&PlayerID>,
+    mut deaths: ResMut<Deaths>,
+) {
+    let player_id = player_query.get(event.0).unwrap().0;
+    deaths.0.insert(PlayerID(player_id));
+}
+
+fn send_goal_events(
+    event: On<GoalEvent>,
+    player_query: Query<&PlayerID>,
+    mut goals: ResMut<Goals>,
+) {
+    let player_id = player_query.get(event.0).unwrap().0;
+    goals.0 = Some(PlayerID(player_id));
+}
+
+fn send_arrow_updates(
+    mut arrow_events: ResMut<ArrowEvents>,
+    arrow_query: Query<(Entity, &Transform), (Changed<Transform>, With<Arrow>)>,
+) {
+    for (entity, transform) in arrow_query.iter() {
+        arrow_events.0.push(ArrowEvent::Updated {
+            id: entity.index_u32(),
+            position: transform.translation,
+            rotation: transform.rotation,
+        });
+    }
+}
+
+fn send_arrow_despawns(
+    mut removals: RemovedComponents<Arrow>,
+    mut arrow_events: ResMut<ArrowEvents>,
+) {
+    for entity in removals.read() {
+        arrow_events.0.push(ArrowEvent::Despawned(entity.index_u32()));
+    }
+}
\ No newline at end of file
@@ -6,3 +6,5 @@ mod end_menu;
 pub use end_menu::*;
 mod game;
 pub use game::*;
+mod replay;
+pub use replay::*;
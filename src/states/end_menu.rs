@@ -1,4 +1,4 @@
-use crate::client::GameConnection;
+use crate::client::{GameConnection, SpectateMatch};
 use crate::states::ButtonBundle;
 use crate::{AppState, AutoDespawn, GameResults};
 use bevy::prelude::*;
@@ -23,6 +23,18 @@ impl Plugin for EndMenuPlugin {
 fn setup(mut commands: Commands, game_results: Res<GameResults>, game_connection: Res<GameConnection>, asset_server: Res<AssetServer>) {
     commands.spawn((Camera2d::default(), AutoDespawn(AppState::EndMenu)));
 
+    let result_text = match game_results.winner {
+        Some(player_id) if game_connection.spectator => format!("Player {} wins!", player_id),
+        Some(player_id) => {
+            if player_id == game_connection.player_id.0 {
+                "You win!".to_string()
+            } else {
+                "You lose!".to_string()
+            }
+        }
+        None => game_results.reason.clone(),
+    };
+
     commands.spawn((
         AutoDespawn(AppState::EndMenu),
         Sprite {
@@ -55,16 +67,7 @@ fn setup(mut commands: Commands, game_results: Res<GameResults>, game_connection
         },
         children![
             (
-                Text::new(match game_results.winner {
-                    Some(player_id) => {
-                        if player_id == game_connection.player_id.0 {
-                            "You win!"
-                        } else {
-                            "You lose!"
-                        }
-                    },
-                    None => game_results.reason.as_str(),
-                }),
+                Text::new(result_text),
                 TextFont {
                     font: asset_server.load("fonts/LeagueSpartan-Bold.ttf"),
                     font_size: if game_results.winner.is_some() {
@@ -85,7 +88,11 @@ fn setup(mut commands: Commands, game_results: Res<GameResults>, game_connection
                     (
                         PlayAgainButton,
                         ButtonBundle::new(UiRect::right(Val::Px(5.0))),
-                        children![Text::new("Play Again?"),],
+                        children![Text::new(if game_connection.spectator {
+                            "Join Game"
+                        } else {
+                            "Play Again?"
+                        }),],
                     ),
                     (
                         MainMenuButton,
@@ -99,6 +106,7 @@ fn setup(mut commands: Commands, game_results: Res<GameResults>, game_connection
 }
 
 fn button_press(
+    mut commands: Commands,
     mut next_state: ResMut<NextState<AppState>>,
     button_query: Query<
         (
@@ -113,6 +121,8 @@ fn button_press(
         if *interaction != Interaction::Pressed {
             return;
         }
+        // The spectated match is over, so join games as a player from now on
+        commands.remove_resource::<SpectateMatch>();
         if main_menu.is_some() {
             next_state.set(AppState::MainMenu);
         } else if play_again.is_some() {
@@ -6,7 +6,8 @@ use crate::player::{PlayerAnimation, PlayerID};
 use crate::states::game::{BlueScoreMarker, RedScoreMarker, TPSMarker};
 use crate::states::network::TickEvent;
 use crate::world::ChunkMap;
-use crate::{AppState, Arrow, ArrowEvent, AutoDespawn};
+use crate::{AppState, Arrow, ArrowEvent, ArrowID, AutoDespawn, PlayerInfo};
+use bevy::platform::collections::HashMap;
 use bevy::prelude::*;
 
 pub struct GameLoopPlugin;
@@ -14,6 +15,7 @@ pub struct GameLoopPlugin;
 impl Plugin for GameLoopPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<LastTick>()
+            .init_resource::<CrackOverlayAssets>()
             .add_systems(
             Update,
             (
@@ -24,6 +26,7 @@ impl Plugin for GameLoopPlugin {
                 update_item_model.after(update_inventories),
                 update_arrows,
                 update_chunkmap,
+                update_crack_overlays,
                 update_scores,
                 update_scoreboard.after(update_scores),
                 update_tps,
@@ -32,37 +35,60 @@ impl Plugin for GameLoopPlugin {
     }
 }
 
+pub(crate) type PlayerBodyQuery<'w, 's> =
+    Query<'w, 's, &'static mut Transform, (With<PlayerBody>, Without<PlayerID>)>;
+pub(crate) type PlayerHeadQuery<'w, 's> = Query<
+    'w,
+    's,
+    &'static mut Transform,
+    (With<PlayerHead>, Without<PlayerID>, Without<PlayerBody>),
+>;
+
 fn update_player_positions(
     mut tick_events: MessageReader<TickEvent>,
     mut player_query: Query<(&PlayerID, &mut Transform, &Children)>,
-    mut player_body_query: Query<&mut Transform, (With<PlayerBody>, Without<PlayerID>)>,
-    mut player_head_query: Query<
-        &mut Transform,
-        (With<PlayerHead>, Without<PlayerID>, Without<PlayerBody>),
-    >,
+    mut player_body_query: PlayerBodyQuery,
+    mut player_head_query: PlayerHeadQuery,
     children_query: Query<&Children>,
 ) {
     for tick_event in tick_events.read() {
         for (player_id, mut transform, children) in player_query.iter_mut() {
-            let player_info = &tick_event.players[player_id.0 as usize];
+            place_player(
+                &tick_event.players[player_id.0 as usize],
+                &mut transform,
+                children,
+                &mut player_body_query,
+                &mut player_head_query,
+                &children_query,
+            );
+        }
+    }
+}
 
-            transform.translation = player_info.position;
+/// Moves a player's model to their position and turns its body and head the way they look
+pub(crate) fn place_player(
+    player_info: &PlayerInfo,
+    transform: &mut Transform,
+    children: &Children,
+    player_body_query: &mut PlayerBodyQuery,
+    player_head_query: &mut PlayerHeadQuery,
+    children_query: &Query<&Children>,
+) {
+    transform.translation = player_info.position;
 
-            for child in children.iter() {
-                let Ok(mut body_transform) = player_body_query.get_mut(child) else {
-                    continue;
-                };
-                // Set body rotation yaw only
-                body_transform.rotation = Quat::from_rotation_y(player_info.yaw);
+    for child in children.iter() {
+        let Ok(mut body_transform) = player_body_query.get_mut(child) else {
+            continue;
+        };
+        // Set body rotation yaw only
+        body_transform.rotation = Quat::from_rotation_y(player_info.yaw);
 
-                for grandchild in children_query.iter_descendants(child) {
-                    let Ok(mut head_transform) = player_head_query.get_mut(grandchild) else {
-                        continue;
-                    };
-                    // Set head rotation pitch only
-                    head_transform.rotation = Quat::from_rotation_x(player_info.pitch);
-                }
-            }
+        for grandchild in children_query.iter_descendants(child) {
+            let Ok(mut head_transform) = player_head_query.get_mut(grandchild) else {
+                continue;
+            };
+            // Set head rotation pitch only
+            head_transform.rotation = Quat::from_rotation_x(player_info.pitch);
         }
     }
 }
@@ -119,6 +145,18 @@ fn stop_hand_animations(anim_player: &mut AnimationPlayer) {
     }
 }
 
+/// Puts the body in the pose of a stance, which stays on while the player uses their hands
+fn set_pose(anim_player: &mut AnimationPlayer, pose: Option<u32>) {
+    for id in [PLAYER_ANIMATION_INDICES.sneak, PLAYER_ANIMATION_INDICES.sprint] {
+        if Some(id) != pose && anim_player.is_playing_animation(id.into()) {
+            anim_player.stop(id.into());
+        }
+    }
+    if let Some(id) = pose {
+        anim_player.play(id.into());
+    }
+}
+
 fn update_animations(
     mut tick_events: MessageReader<TickEvent>,
     player_query: Query<
@@ -202,7 +240,13 @@ fn update_animations(
                         stop_hand_animations(&mut anim_player);
                         anim_player.start(PLAYER_ANIMATION_INDICES.eat.into());
                     }
-                    _ => {}
+                    PlayerAnimation::None => set_pose(&mut anim_player, None),
+                    PlayerAnimation::Sneak => {
+                        set_pose(&mut anim_player, Some(PLAYER_ANIMATION_INDICES.sneak))
+                    }
+                    PlayerAnimation::Sprint => {
+                        set_pose(&mut anim_player, Some(PLAYER_ANIMATION_INDICES.sprint))
+                    }
                 }
             }
         }
@@ -263,38 +307,39 @@ fn update_arrows(
     assets: Res<AssetServer>,
     mut commands: Commands,
 ) {
+    // Arrows spawned by earlier ticks in this batch aren't in the query yet
+    let mut spawned = HashMap::new();
     for tick_event in tick_events.read(){
         for arrow_event in tick_event.arrow_events.iter() {
             match arrow_event {
                 ArrowEvent::Updated { id, position, rotation } => {
-                    let rotation = rotation * Quat::from_rotation_z(std::f32::consts::FRAC_PI_2); // Adjust for model orientation
+                    let model_rotation = arrow_model_rotation(*rotation);
                     let mut updated = false;
                     for (_, arrow, mut transform) in arrow_query.iter_mut() {
                         if arrow.id != *id {
                             continue;
                         }
                         transform.translation = *position;
-                        transform.rotation = rotation;
+                        transform.rotation = model_rotation;
+                        updated = true;
+                    }
+                    if let Some(&entity) = spawned.get(id) {
+                        commands.entity(entity).insert(Transform {
+                            translation: *position,
+                            rotation: model_rotation,
+                            ..default()
+                        });
                         updated = true;
                     }
                     if !updated {
-                        // Spawn new arrow
-                        commands.spawn((
-                            AutoDespawn(AppState::Game),
-                            Arrow {
-                                id: *id,
-                                ..default()
-                            },
-                            Transform {
-                                translation: *position,
-                                rotation,
-                                ..default()
-                            },
-                            SceneRoot(assets.load("models/items/Arrow.gltf#Scene0")),
-                        ));
+                        let entity = spawn_arrow(&mut commands, &assets, *id, *position, *rotation, AppState::Game);
+                        spawned.insert(*id, entity);
                     }
                 }
                 ArrowEvent::Despawned(id) => {
+                    if let Some(entity) = spawned.remove(id) {
+                        commands.entity(entity).despawn();
+                    }
                     for (entity, arrow, _) in arrow_query.iter_mut() {
                         if arrow.id != *id {
                             continue;
@@ -309,14 +354,120 @@ fn update_arrows(
     }
 }
 
+fn arrow_model_rotation(rotation: Quat) -> Quat {
+    rotation * Quat::from_rotation_z(std::f32::consts::FRAC_PI_2) // Adjust for model orientation
+}
+
+pub(crate) fn spawn_arrow(
+    commands: &mut Commands,
+    assets: &AssetServer,
+    id: ArrowID,
+    position: Vec3,
+    rotation: Quat,
+    state: AppState,
+) -> Entity {
+    commands
+        .spawn((
+            AutoDespawn(state),
+            Arrow { id, ..default() },
+            Transform {
+                translation: position,
+                rotation: arrow_model_rotation(rotation),
+                ..default()
+            },
+            SceneRoot(assets.load("models/items/Arrow.gltf#Scene0")),
+        ))
+        .id()
+}
+
+/// Number of distinct looks of a block that is being broken
+const CRACK_STAGES: f32 = 10.0;
+
+/// Darkens the block a player is breaking, more with every crack stage
+#[derive(Component)]
+pub(crate) struct CrackOverlay(PlayerID);
+
+/// The mesh shared by all crack overlays and a material for every crack stage
+#[derive(Resource)]
+struct CrackOverlayAssets {
+    mesh: Handle<Mesh>,
+    materials: Vec<Handle<StandardMaterial>>,
+}
+
+impl FromWorld for CrackOverlayAssets {
+    fn from_world(world: &mut World) -> Self {
+        // Slightly larger than the block so it isn't hidden by its faces
+        let mesh = world.resource_mut::<Assets<Mesh>>().add(Cuboid::from_length(1.01));
+        let mut materials = world.resource_mut::<Assets<StandardMaterial>>();
+        let materials = (0..=CRACK_STAGES as u32)
+            .map(|stage| {
+                materials.add(StandardMaterial {
+                    base_color: Color::srgba(0.0, 0.0, 0.0, 0.6 * stage as f32 / CRACK_STAGES),
+                    alpha_mode: AlphaMode::Blend,
+                    unlit: true,
+                    ..default()
+                })
+            })
+            .collect();
+        Self { mesh, materials }
+    }
+}
+
+fn update_crack_overlays(
+    mut tick_events: MessageReader<TickEvent>,
+    mut overlay_query: Query<(
+        Entity,
+        &CrackOverlay,
+        &mut Transform,
+        &mut MeshMaterial3d<StandardMaterial>,
+    )>,
+    overlay_assets: Res<CrackOverlayAssets>,
+    state: Res<State<AppState>>,
+    mut commands: Commands,
+) {
+    let Some(tick_event) = tick_events.read().last() else {
+        return;
+    };
+    for (i, player_info) in tick_event.players.iter().enumerate() {
+        let player_id = PlayerID(i as u16);
+        let overlay = overlay_query
+            .iter_mut()
+            .find(|(_, overlay, _, _)| overlay.0 == player_id);
+        let Some(breaking) = player_info.breaking else {
+            if let Some((entity, ..)) = overlay {
+                commands.entity(entity).despawn();
+            }
+            continue;
+        };
+
+        let stage = (breaking.progress * CRACK_STAGES).floor() as usize;
+        let material =
+            overlay_assets.materials[stage.min(overlay_assets.materials.len() - 1)].clone();
+        // Block p covers [p, p + 1] on every axis
+        let translation = breaking.block_pos.as_vec3() + Vec3::splat(0.5);
+        if let Some((_, _, mut transform, mut overlay_material)) = overlay {
+            transform.translation = translation;
+            if overlay_material.0 != material {
+                overlay_material.0 = material;
+            }
+        } else {
+            commands.spawn((
+                AutoDespawn(state.get().clone()),
+                CrackOverlay(player_id),
+                Mesh3d(overlay_assets.mesh.clone()),
+                MeshMaterial3d(material),
+                Transform::from_translation(translation),
+            ));
+        }
+    }
+}
+
 fn update_chunkmap(
     mut tick_events: MessageReader<TickEvent>,
     mut chunkmap: Single<&mut ChunkMap>,
 ) {
     for tick_event in tick_events.read() {
-        for (block_pos, block_type) in tick_event.block_updates.iter() {
-            let _ = chunkmap.set_block(*block_pos, *block_type);
-        }
+        chunkmap.apply_diff(&tick_event.block_updates);
     }
 }
 
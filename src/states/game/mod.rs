@@ -1,9 +1,9 @@
-use crate::player::{PLAYER_ANIMATION_INDICES, PlayerBody, PlayerBundle, PlayerHand, SPAWN_POSITIONS, SPAWN_ROTATIONS, Inventory};
+use crate::player::{PLAYER_ANIMATION_INDICES, PlayerBody, PlayerBundle, PlayerHand, Inventory, Health, Score};
 use crate::player::{PlayerHead, PlayerID};
-use crate::world::{WorldPlugin, init_map};
+use crate::world::WorldPlugin;
 use crate::{
-    AppState, AutoDespawn, ControlServer,
-    states::game::{gameloop::GameLoopPlugin, network::NetworkPlugin},
+    AppState, AutoDespawn, ControlServer, WorldSnapshot,
+    states::game::{gameloop::{GameLoopPlugin, spawn_arrow}, network::NetworkPlugin},
 };
 use bevy::scene::SceneInstanceReady;
 use bevy::{
@@ -15,7 +15,7 @@ use bevy::{
 use bevy_inspector_egui::bevy_egui;
 use crate::client::GameConnection;
 
-mod gameloop;
+pub(crate) mod gameloop;
 pub mod network;
 
 #[derive(ScheduleLabel, Hash, PartialEq, Eq, Clone, Debug)]
@@ -78,6 +78,64 @@ fn setup(
     assets: Res<AssetServer>,
     control_server: Res<ControlServer>,
     game_connection: Res<GameConnection>,
+) {
+    spawn_arena(
+        &mut commands,
+        &mut graphs,
+        &assets,
+        &game_connection.snapshot,
+        (!game_connection.spectator).then_some(game_connection.player_id),
+        AppState::Game,
+    );
+
+    if game_connection.spectator {
+        commands.spawn((
+            AutoDespawn(AppState::Game),
+            Text2d::new(format!("Spectating match {}", game_connection.match_id)),
+            TextFont::default(),
+            Node {
+                margin: UiRect::default().with_top(Val::Px(20.0)),
+                height: Val::Px(90.0),
+                width: Val::Percent(100.0),
+                ..default()
+            },
+        ));
+        return;
+    }
+
+    commands.spawn((
+        AutoDespawn(AppState::Game),
+        Text2d::new("Client status: "),
+        TextFont::default(),
+        children![(
+            ClientStatusMarker,
+            TextSpan(
+                (if control_server.client.is_some() {
+                    "Connected"
+                } else {
+                    "Disconnected"
+                })
+                .to_string()
+            ),
+        ),],
+        Node {
+            margin: UiRect::default().with_top(Val::Px(20.0)),
+            height: Val::Px(90.0),
+            width: Val::Percent(100.0),
+            ..default()
+        },
+    ));
+}
+
+/// Spawns the camera, scoreboard, map, players and arrows that the game loop drives, as they are in the snapshot.
+/// `local_player` gets the player model, everyone else the opponent model.
+pub(crate) fn spawn_arena(
+    commands: &mut Commands,
+    graphs: &mut Assets<AnimationGraph>,
+    assets: &AssetServer,
+    snapshot: &WorldSnapshot,
+    local_player: Option<PlayerID>,
+    state: AppState,
 ) {
     commands.spawn((
         Camera3d::default(),
@@ -88,11 +146,11 @@ fn setup(
         Msaa::Off,
         Transform::from_xyz(-2.5, 4.5, 9.0).looking_at(Vec3::ZERO, Vec3::Y),
         bevy_egui::PrimaryEguiContext,
-        AutoDespawn(AppState::Game),
+        AutoDespawn(state.clone()),
     ));
 
     commands.spawn((
-        AutoDespawn(AppState::Game),
+        AutoDespawn(state.clone()),
         Node {
             display: Display::Flex,
             justify_content: JustifyContent::Center,
@@ -125,7 +183,7 @@ fn setup(
     ));
 
     commands.spawn((
-        AutoDespawn(AppState::Game),
+        AutoDespawn(state.clone()),
         Text2d::new("TPS: "),
         TextFont::default(),
         children![(TPSMarker, TextSpan("0".to_string()))],
@@ -136,38 +194,20 @@ fn setup(
         },
     ));
 
-    commands.spawn((
-        AutoDespawn(AppState::Game),
-        Text2d::new("Client status: "),
-        TextFont::default(),
-        children![(
-            ClientStatusMarker,
-            TextSpan(
-                (if control_server.client.is_some() {
-                    "Connected"
-                } else {
-                    "Disconnected"
-                })
-                .to_string()
-            ),
-        ),],
-        Node {
-            margin: UiRect::default().with_top(Val::Px(20.0)),
-            height: Val::Px(90.0),
-            width: Val::Percent(100.0),
-            ..default()
-        },
-    ));
+    commands.spawn((snapshot.map.clone(), AutoDespawn(state.clone())));
 
-    commands.spawn((init_map(), AutoDespawn(AppState::Game)));
+    for (id, (position, rotation)) in snapshot.arrows.iter() {
+        spawn_arrow(commands, assets, *id, *position, *rotation, state.clone());
+    }
 
     for i in 0..2_i32 {
+        let player_info = &snapshot.players[i as usize];
         let mut body_transform = Transform::from_xyz(0.0, -0.9, 0.0);
-        body_transform.rotation = Quat::from_rotation_y(SPAWN_ROTATIONS[i as usize]);
+        body_transform.rotation = Quat::from_rotation_y(player_info.yaw);
 
-        let gltf_path = format!("models/{}.gltf#Scene0", if i == game_connection.player_id.0 as i32 { "player" } else { "opponent" });
+        let gltf_path = format!("models/{}.gltf#Scene0", if Some(PlayerID(i as u16)) == local_player { "player" } else { "opponent" });
         let mut graph = AnimationGraph::new();
-        for i in 0..5 {
+        for i in 0..7 {
             graph.add_clip(
                 assets.load(GltfAssetLabel::Animation(i).from_asset(gltf_path.clone())),
                 match i {
@@ -182,11 +222,14 @@ fn setup(
             .spawn((
                 PlayerBundle {
                     id: PlayerID(i as u16),
-                    transform: Transform::from_translation(SPAWN_POSITIONS[i as usize]),
+                    health: Health(snapshot.health[i as usize]),
+                    inventory: snapshot.inventories[i as usize].clone(),
+                    score: Score(snapshot.scores[i as usize]),
+                    transform: Transform::from_translation(player_info.position),
                     ..default()
                 },
                 Visibility::default(),
-                AutoDespawn(AppState::Game),
+                AutoDespawn(state.clone()),
             ))
             .with_children(|parent| {
                 parent
@@ -258,17 +301,17 @@ fn mark_entities_on_ready(
     }
 }
 
-fn cursor_grab(mut cursor_opts: Single<&mut CursorOptions, With<PrimaryWindow>>) {
+pub(crate) fn cursor_grab(mut cursor_opts: Single<&mut CursorOptions, With<PrimaryWindow>>) {
     cursor_opts.grab_mode = CursorGrabMode::Confined;
     cursor_opts.visible = false;
 }
 
-fn cursor_ungrab(mut cursor_opts: Single<&mut CursorOptions, With<PrimaryWindow>>) {
+pub(crate) fn cursor_ungrab(mut cursor_opts: Single<&mut CursorOptions, With<PrimaryWindow>>) {
     cursor_opts.grab_mode = CursorGrabMode::None;
     cursor_opts.visible = true;
 }
 
-fn toggle_cursor_grab(
+pub(crate) fn toggle_cursor_grab(
     mut cursor_opts: Single<&mut CursorOptions, With<PrimaryWindow>>,
     keyboard_input: Res<ButtonInput<KeyCode>>,
 ) {
@@ -283,7 +326,7 @@ fn toggle_cursor_grab(
     }
 }
 
-fn move_cam(
+pub(crate) fn move_cam(
     keyboard_input: Res<ButtonInput<KeyCode>>,
     cursor_opts: Single<&CursorOptions, With<PrimaryWindow>>,
     mut mouse_motion: MessageReader<MouseMotion>,
@@ -1,10 +1,13 @@
 use crate::client::GameConnectionMessage;
-use crate::player::{Inventory, Item, PlayerActions, Rotation};
+use crate::map::MapDef;
+use crate::player::{Inventory, Item, PlayerActions, PlayerID, PlayerStance, Rotation};
 use crate::world::BlockType;
-use crate::{AppState, ControlServer, GameResults, TickMessage, client::GameConnection};
+use crate::{
+    AppState, ControlFormat, ControlServer, DamageSource, GameResults, TickMessage, WorldSnapshot,
+    client::GameConnection,
+};
 use bevy::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::io::Write;
 
 #[derive(Resource, Default, Debug)]
 pub struct NetworkState {
@@ -14,18 +17,55 @@ pub struct NetworkState {
 #[derive(Message, Deref)]
 pub struct TickEvent(pub TickMessage);
 
-#[derive(Serialize)]
+/// Events sent to the agent on the control socket, as externally tagged JSON objects one per line.
+/// Every tick starts with `TickStart`, followed by what changed during it, and ends with `TickEnd`
+/// after which the agent should answer with its actions and `EndTick`. The last tick ends with `GameOver` instead.
+#[derive(Serialize, Debug, Clone)]
 pub enum ControlMsgS2C {
+    /// The map of the match, sent right before every `Snapshot`
+    Map(MapDef),
+    /// The whole world before the next `TickStart`, sent before the first tick and when an agent attaches mid-match
+    Snapshot(WorldSnapshot),
     TickStart {
         tick: u64,
+        /// The ID of the agent's player
+        player_id: u16,
+        /// The actions the opponent's last tick was simulated with
         opponent_prev_actions: PlayerActions,
         player_position: Vec3,
         opponent_position: Vec3,
+        player_rotation: Rotation,
+        opponent_rotation: Rotation,
+        /// How far each player's bow is drawn, from 0 to 1
+        player_bow_charge: f32,
+        opponent_bow_charge: f32,
+        player_stance: PlayerStance,
+        opponent_stance: PlayerStance,
+        /// How strong an attack with each player's held item would be, from 0 to 1
+        player_attack_charge: f32,
+        opponent_attack_charge: f32,
+        /// Ticks left until the time limit, `None` if the match has no limit or is in sudden death
+        ticks_remaining: Option<u64>,
+        sudden_death: bool,
     },
     HealthUpdate {
         player_id: u16,
         new_health: f32,
     },
+    /// Sent for every melee hit that landed, before the `Damage` it caused
+    Hit {
+        attacker: u16,
+        target: u16,
+        damage: f32,
+        knockback: Vec3,
+        critical: bool,
+    },
+    /// Sent for every time a player lost health, before their `HealthUpdate`
+    Damage {
+        player_id: u16,
+        amount: f32,
+        source: DamageSource,
+    },
     Death {
         player_id: u16,
     },
@@ -33,10 +73,115 @@ pub enum ControlMsgS2C {
         player_id: u16,
     },
     BlockUpdate(IVec3, BlockType),
+    /// Sent every tick a player spends breaking a block
+    BlockBreaking {
+        player_id: u16,
+        block_pos: IVec3,
+        /// How far the block is broken, from 0 to 1
+        progress: f32,
+    },
     InventoryUpdate {
         player_id: u16,
         new_contents: Inventory,
     },
+    TickEnd {
+        tick: u64,
+    },
+    GameOver {
+        winner: Option<u16>,
+        reason: String,
+    },
+    /// Sent when a line from the agent couldn't be parsed, the line is ignored
+    Error(String),
+}
+
+impl ControlMsgS2C {
+    /// Splits a tick into the events an agent playing as `player_id` receives
+    pub fn from_tick(player_id: PlayerID, msg: &TickMessage) -> Vec<ControlMsgS2C> {
+        let player = player_id.0 as usize;
+        let opponent = player ^ 1;
+        let mut events = vec![ControlMsgS2C::TickStart {
+            tick: msg.tick,
+            player_id: player_id.0,
+            opponent_prev_actions: msg.actions[opponent],
+            player_position: msg.players[player].position,
+            opponent_position: msg.players[opponent].position,
+            player_rotation: Rotation {
+                yaw: msg.players[player].yaw,
+                pitch: msg.players[player].pitch,
+            },
+            opponent_rotation: Rotation {
+                yaw: msg.players[opponent].yaw,
+                pitch: msg.players[opponent].pitch,
+            },
+            player_bow_charge: msg.players[player].bow_charge,
+            opponent_bow_charge: msg.players[opponent].bow_charge,
+            player_stance: msg.players[player].stance,
+            opponent_stance: msg.players[opponent].stance,
+            player_attack_charge: msg.players[player].attack_charge,
+            opponent_attack_charge: msg.players[opponent].attack_charge,
+            ticks_remaining: msg.ticks_remaining,
+            sudden_death: msg.sudden_death,
+        }];
+        for hit in msg.hits.iter() {
+            events.push(ControlMsgS2C::Hit {
+                attacker: hit.attacker.0,
+                target: hit.target.0,
+                damage: hit.damage,
+                knockback: hit.knockback,
+                critical: hit.critical,
+            });
+        }
+        for damage in msg.damage.iter() {
+            events.push(ControlMsgS2C::Damage {
+                player_id: damage.player_id.0,
+                amount: damage.amount,
+                source: damage.source,
+            });
+        }
+        for (id, info) in msg.players.iter().enumerate() {
+            if let Some(new_health) = info.health_update {
+                events.push(ControlMsgS2C::HealthUpdate {
+                    player_id: id as u16,
+                    new_health,
+                });
+            }
+        }
+        for id in msg.deaths.iter() {
+            events.push(ControlMsgS2C::Death { player_id: id.0 });
+        }
+        if let Some(id) = msg.goals {
+            events.push(ControlMsgS2C::Goal { player_id: id.0 });
+        }
+        for (pos, block_type) in msg.block_updates.iter() {
+            events.push(ControlMsgS2C::BlockUpdate(*pos, *block_type));
+        }
+        for (id, info) in msg.players.iter().enumerate() {
+            if let Some(breaking) = info.breaking {
+                events.push(ControlMsgS2C::BlockBreaking {
+                    player_id: id as u16,
+                    block_pos: breaking.block_pos,
+                    progress: breaking.progress,
+                });
+            }
+        }
+        for (id, info) in msg.players.iter().enumerate() {
+            if let Some(new_contents) = &info.inventory_update {
+                events.push(ControlMsgS2C::InventoryUpdate {
+                    player_id: id as u16,
+                    new_contents: new_contents.clone(),
+                });
+            }
+        }
+        events.push(match &msg.game_results {
+            Some(results) => ControlMsgS2C::GameOver {
+                winner: results.winner,
+                reason: results.reason.clone(),
+            },
+            None => ControlMsgS2C::TickEnd { tick: msg.tick },
+        });
+        events
+    }
 }
 
 /// Note: Different actions that use the player's hands cannot be executed together in the same tick. The action that is received first will be executed, and later actions will be discarded.
@@ -47,6 +192,10 @@ pub enum ControlMsgC2S {
     MoveLeft,
     MoveRight,
     Jump,
+    /// Only has an effect while moving forward
+    Sprint,
+    /// Takes precedence over sprinting
+    Sneak,
     /// Rotations do not accumulate within a tick; the last one received is used.
     Rotate(Rotation),
     SelectItem(Item),
@@ -105,12 +254,26 @@ fn start_tick(
         return;
     }
 
-    let Ok(msg) = game_connection.receiver_rx.try_recv() else {
-        return;
-    };
-    let data = match msg {
-        workflow_websocket::client::Message::Binary(data) => data,
-        _ => {
+    // Players answer every tick before the next one is sent, while spectators catch up on everything received
+    loop {
+        let Ok(msg) = game_connection.receiver_rx.try_recv() else {
+            return;
+        };
+        let data = match msg {
+            workflow_websocket::client::Message::Binary(data) => data,
+            _ => {
+                let _ = game_connection
+                    .sender_tx
+                    .send(GameConnectionMessage::Disconnect);
+                commands.insert_resource(GameResults {
+                    winner: None,
+                    reason: "Disconnected".to_string(),
+                });
+                commands.set_state(AppState::EndMenu);
+                return;
+            }
+        };
+        let Ok(msg) = postcard::from_bytes::<TickMessage>(&data) else {
             let _ = game_connection
                 .sender_tx
                 .send(GameConnectionMessage::Disconnect);
@@ -120,37 +283,79 @@ fn start_tick(
             });
             commands.set_state(AppState::EndMenu);
             return;
+        };
+
+        if game_connection.spectator {
+            if let Some(game_results) = msg.game_results {
+                commands.insert_resource(game_results);
+                commands.set_state(AppState::EndMenu);
+                return;
+            }
+            commands.write_message(TickEvent(msg));
+            continue;
         }
-    };
-    let Ok(msg) = postcard::from_bytes::<TickMessage>(&data) else {
-        let _ = game_connection
-            .sender_tx
-            .send(GameConnectionMessage::Disconnect);
-        commands.insert_resource(GameResults {
-            winner: None,
-            reason: "Disconnected".to_string(),
-        });
-        commands.set_state(AppState::EndMenu);
-        return;
-    };
-    let tick_start_msg = format!("[{},{}]", game_connection.player_id.0, serde_json::to_string(&msg).unwrap()).into_bytes();
-    if let Some(client) = &mut control_server.client {
-        client.write(tick_start_msg.as_slice()).unwrap();
-    }
 
-    if let Some(game_results) = msg.game_results {
-        commands.insert_resource(game_results);
-        commands.set_state(AppState::EndMenu);
-        return;
-    }
+        // Agents get the whole world once per match and when they attach between ticks, and the ticks after it
+        let world = match control_server.world.take() {
+            Some(world) if world.tick == msg.tick => world,
+            _ => {
+                control_server.map = Some(game_connection.config.map.clone());
+                control_server.send_world = true;
+                game_connection.snapshot.clone()
+            }
+        };
+        if std::mem::take(&mut control_server.send_world)
+            && let Some(client) = &control_server.client
+        {
+            client.send_line(
+                serde_json::to_string(&ControlMsgS2C::Map(game_connection.config.map.clone()))
+                    .unwrap()
+                    .as_bytes(),
+            );
+            client.send_line(
+                serde_json::to_string(&ControlMsgS2C::Snapshot(world.clone()))
+                    .unwrap()
+                    .as_bytes(),
+            );
+        }
+
+        let tick_start_msg = match control_server.format {
+            ControlFormat::Events => ControlMsgS2C::from_tick(game_connection.player_id, &msg)
+                .iter()
+                .map(|event| serde_json::to_string(event).unwrap())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            ControlFormat::Raw => format!(
+                "[{},{}]",
+                game_connection.player_id.0,
+                serde_json::to_string(&msg).unwrap()
+            ),
+        }
+        .into_bytes();
+        if let Some(client) = &control_server.client {
+            client.send_line(tick_start_msg.as_slice());
+        }
 
-    // Set this after so that clients that connect in the end menu don't have stale tick start messages
-    control_server.tick_start_messages = Some(tick_start_msg);
+        if let Some(game_results) = msg.game_results {
+            commands.insert_resource(game_results);
+            commands.set_state(AppState::EndMenu);
+            return;
+        }
 
-    commands.write_message(TickEvent(msg));
+        control_server.world = Some(world);
+        // Set this after so that clients that connect in the end menu don't have stale tick start messages
+        control_server.tick_start_messages = Some(tick_start_msg);
+        control_server.pending_tick = Some(msg.clone());
+
+        commands.write_message(TickEvent(msg));
+        return;
+    }
 }
 
 fn end_tick(game_connection: Res<GameConnection>, mut control_server: ResMut<ControlServer>) {
+    if game_connection.spectator {
+        return;
+    }
     let mut message_buffer = control_server.message_buffer.lock().unwrap();
     let Some(end_idx) = message_buffer
         .iter()
@@ -163,6 +368,11 @@ fn end_tick(game_connection: Res<GameConnection>, mut control_server: ResMut<Con
     message_buffer.drain(..=end_idx);
     drop(message_buffer);
     control_server.tick_start_messages = None;
+    if let Some(msg) = control_server.pending_tick.take()
+        && let Some(world) = &mut control_server.world
+    {
+        world.apply(&msg);
+    }
 
     let mut actions = PlayerActions::default();
     for msg in messages {
@@ -172,6 +382,8 @@ fn end_tick(game_connection: Res<GameConnection>, mut control_server: ResMut<Con
             ControlMsgC2S::MoveLeft => actions.set(PlayerActions::MOVE_LEFT),
             ControlMsgC2S::MoveRight => actions.set(PlayerActions::MOVE_RIGHT),
             ControlMsgC2S::Jump => actions.set(PlayerActions::JUMP),
+            ControlMsgC2S::Sprint => actions.set(PlayerActions::SPRINT),
+            ControlMsgC2S::Sneak => actions.set(PlayerActions::SNEAK),
             ControlMsgC2S::Rotate(rotation) => actions.rotation = rotation,
             ControlMsgC2S::SelectItem(item) => actions.item_change = Some(item),
             ControlMsgC2S::Attack => actions.checked_set(PlayerActions::ATTACK),
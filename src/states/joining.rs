@@ -1,4 +1,5 @@
-use crate::{client::GameConnection, AppState, AutoDespawn, SERVER_URL};
+use crate::client::{ClientInfo, ConnectionError, GameConnection, ServerUrl, SpectateMatch};
+use crate::{AppState, AutoDespawn};
 use bevy::{
     prelude::*,
     tasks::{block_on, futures_lite::future, AsyncComputeTaskPool, Task},
@@ -33,14 +34,29 @@ fn setup(mut commands: Commands) {
 #[derive(Component)]
 struct ConnectingTask(Task<Result<GameConnection, anyhow::Error>>);
 
-fn start_connection(mut commands: Commands, task_query: Query<&ConnectingTask>) {
+fn start_connection(
+    mut commands: Commands,
+    task_query: Query<&ConnectingTask>,
+    server_url: Res<ServerUrl>,
+    client_info: Res<ClientInfo>,
+    spectate: Option<Res<SpectateMatch>>,
+) {
     if task_query.single().is_ok() {
         // If there's already a connection task, don't start a new one
         return;
     }
     println!("Starting connection to game server...");
+    commands.remove_resource::<ConnectionError>();
     let task_pool = AsyncComputeTaskPool::get();
-    let task = task_pool.spawn(async { GameConnection::connect(SERVER_URL).await });
+    let url = server_url.0.clone();
+    let info = client_info.clone();
+    let task = match spectate {
+        Some(spectate) => {
+            let match_id = spectate.0;
+            task_pool.spawn(async move { GameConnection::spectate(&url, info, match_id).await })
+        }
+        None => task_pool.spawn(async move { GameConnection::connect(&url, info).await }),
+    };
     commands.spawn(ConnectingTask(task));
 }
 
@@ -58,7 +74,9 @@ fn poll_connection(
                     next_state.set(AppState::Game);
                 }
                 Err(e) => {
-                    eprintln!("Failed to connect: {}", e);
+                    eprintln!("Failed to connect: {:#}", e);
+                    commands.insert_resource(ConnectionError(format!("{:#}", e)));
+                    commands.remove_resource::<SpectateMatch>();
                     next_state.set(AppState::MainMenu);
                 }
             }
@@ -1,3 +1,4 @@
+use crate::client::ConnectionError;
 use crate::{AppState, AutoDespawn, ControlServer};
 use bevy::prelude::*;
 
@@ -64,6 +65,7 @@ impl Plugin for MainMenuPlugin {
 fn setup(
     mut commands: Commands,
     server: Option<Res<ControlServer>>,
+    connection_error: Option<Res<ConnectionError>>,
     asset_server: Res<AssetServer>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
@@ -157,6 +159,22 @@ fn setup(
         },
         BackgroundColor(Color::Srgba(color)),
     ));
+
+    if let Some(error) = connection_error {
+        commands.spawn((
+            AutoDespawn(AppState::MainMenu),
+            Node {
+                position_type: PositionType::Absolute,
+                bottom: Val::Px(40.0),
+                left: Val::Px(50.0),
+                ..default()
+            },
+            children![(
+                Text::new(format!("Couldn't join the game: {}", error.0)),
+                TextColor(Color::Srgba(Srgba::RED)),
+            ),],
+        ));
+    }
 }
 
 fn play_button_press(
@@ -0,0 +1,278 @@
+use crate::player::{Health, Inventory, PlayerID, Score};
+use crate::replay::{ReplayHeader, ReplayReader, ReplayRecord};
+use crate::states::game::gameloop::{
+    CrackOverlay, PlayerBodyQuery, PlayerHeadQuery, place_player, spawn_arrow,
+};
+use crate::states::game::{cursor_grab, move_cam, spawn_arena, toggle_cursor_grab};
+use crate::states::network::TickEvent;
+use crate::world::ChunkMap;
+use crate::{AppState, Arrow, AutoDespawn, TICK_RATE, TickMessage, WorldSnapshot};
+use bevy::input::InputSystems;
+use bevy::prelude::*;
+use std::path::Path;
+
+/// Ticks shown per second at 1x speed
+const PLAYBACK_TPS: f32 = TICK_RATE as f32;
+/// Ticks skipped by the seek keys
+const SEEK_TICKS: usize = 600;
+
+/// Plays back a recorded match through the game loop.
+/// Requires the [`ReplayPlayback`] resource to be inserted before entering [`AppState::Replay`].
+///
+/// Controls: P pauses, Left/Right step a tick, Up/Down change the speed,
+/// PageUp/PageDown seek, Home/End jump to the start/end.
+pub struct ReplayPlugin;
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(OnEnter(AppState::Replay), (setup, cursor_grab))
+            .add_systems(
+                PreUpdate,
+                (control_playback, reset_world, advance_playback)
+                    .chain()
+                    .after(InputSystems)
+                    .run_if(in_state(AppState::Replay)),
+            )
+            .add_systems(
+                Update,
+                (toggle_cursor_grab, move_cam, update_status).run_if(in_state(AppState::Replay)),
+            );
+    }
+}
+
+#[derive(Resource)]
+pub struct ReplayPlayback {
+    header: ReplayHeader,
+    /// The world before the first tick
+    initial: WorldSnapshot,
+    ticks: Vec<TickMessage>,
+    /// Number of ticks that were sent to the game loop
+    shown: usize,
+    /// Number of ticks that should have been sent to the game loop
+    target: usize,
+    /// Set when seeking backwards, since the world can only be rebuilt from the first tick
+    needs_reset: bool,
+    paused: bool,
+    speed: f32,
+    /// Fraction of a tick carried over between frames
+    elapsed_ticks: f32,
+}
+
+impl ReplayPlayback {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let reader = ReplayReader::open(path)?;
+        let header = reader.header.clone();
+        let mut initial = None;
+        let mut ticks = Vec::new();
+        for record in reader {
+            match record? {
+                ReplayRecord::Snapshot(snapshot) if initial.is_none() => initial = Some(snapshot),
+                ReplayRecord::Tick(tick) => ticks.push(tick),
+                _ => {}
+            }
+        }
+        // Replays always start with a snapshot, but the match config describes the same world
+        let initial = initial.unwrap_or_else(|| WorldSnapshot::initial(&header.config));
+        println!(
+            "Loaded replay of match {} with {} ticks",
+            header.match_id,
+            ticks.len()
+        );
+
+        Ok(ReplayPlayback {
+            header,
+            initial,
+            ticks,
+            shown: 0,
+            target: 0,
+            needs_reset: false,
+            paused: false,
+            speed: 1.0,
+            elapsed_ticks: 0.0,
+        })
+    }
+
+    fn seek(&mut self, target: usize) {
+        self.target = target.min(self.ticks.len());
+        if self.target < self.shown {
+            self.needs_reset = true;
+        }
+        self.elapsed_ticks = 0.0;
+    }
+}
+
+#[derive(Component)]
+struct ReplayStatusMarker;
+
+fn setup(
+    mut commands: Commands,
+    mut graphs: ResMut<Assets<AnimationGraph>>,
+    assets: Res<AssetServer>,
+    playback: Res<ReplayPlayback>,
+) {
+    spawn_arena(
+        &mut commands,
+        &mut graphs,
+        &assets,
+        &playback.initial,
+        None,
+        AppState::Replay,
+    );
+
+    commands.spawn((
+        AutoDespawn(AppState::Replay),
+        Text2d::new(""),
+        TextFont::default(),
+        children![(ReplayStatusMarker, TextSpan::default())],
+        Node {
+            margin: UiRect::default().with_top(Val::Px(20.0)),
+            height: Val::Px(90.0),
+            width: Val::Percent(100.0),
+            ..default()
+        },
+    ));
+}
+
+fn control_playback(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut playback: ResMut<ReplayPlayback>,
+) {
+    let target = playback.target;
+    let end = playback.ticks.len();
+    for keycode in keyboard_input.get_just_pressed() {
+        match keycode {
+            KeyCode::KeyP => {
+                playback.paused = !playback.paused;
+                // Start over when resuming at the end
+                if !playback.paused && target == end {
+                    playback.seek(0);
+                }
+            }
+            KeyCode::ArrowRight => {
+                playback.paused = true;
+                playback.seek(target + 1);
+            }
+            KeyCode::ArrowLeft => {
+                playback.paused = true;
+                playback.seek(target.saturating_sub(1));
+            }
+            KeyCode::ArrowUp => playback.speed = (playback.speed * 2.0).min(64.0),
+            KeyCode::ArrowDown => playback.speed = (playback.speed / 2.0).max(1.0 / 16.0),
+            KeyCode::PageUp => playback.seek(target + SEEK_TICKS),
+            KeyCode::PageDown => playback.seek(target.saturating_sub(SEEK_TICKS)),
+            KeyCode::Home => playback.seek(0),
+            KeyCode::End => playback.seek(end),
+            _ => {}
+        }
+    }
+}
+
+/// Puts the world back into its state before the first tick
+fn reset_world(
+    mut playback: ResMut<ReplayPlayback>,
+    mut chunk_map: Single<&mut ChunkMap>,
+    mut players: Query<(
+        &PlayerID,
+        &mut Transform,
+        &Children,
+        &mut Score,
+        &mut Health,
+        &mut Inventory,
+    )>,
+    mut player_body_query: PlayerBodyQuery,
+    mut player_head_query: PlayerHeadQuery,
+    children_query: Query<&Children>,
+    arrows: Query<Entity, With<Arrow>>,
+    crack_overlays: Query<Entity, With<CrackOverlay>>,
+    assets: Res<AssetServer>,
+    mut commands: Commands,
+) {
+    if !playback.needs_reset {
+        return;
+    }
+    let initial = &playback.initial;
+    // Only the chunks changed since the start need new meshes
+    let changes = chunk_map.diff(&initial.map);
+    chunk_map.apply_diff(&changes);
+    for (player_id, mut transform, children, mut score, mut health, mut inventory) in
+        players.iter_mut()
+    {
+        let i = player_id.0 as usize;
+        place_player(
+            &initial.players[i],
+            &mut transform,
+            children,
+            &mut player_body_query,
+            &mut player_head_query,
+            &children_query,
+        );
+        score.0 = initial.scores[i];
+        health.0 = initial.health[i];
+        *inventory = initial.inventories[i].clone();
+    }
+    for entity in arrows.iter().chain(crack_overlays.iter()) {
+        commands.entity(entity).despawn();
+    }
+    for (id, (position, rotation)) in initial.arrows.iter() {
+        spawn_arrow(
+            &mut commands,
+            &assets,
+            *id,
+            *position,
+            *rotation,
+            AppState::Replay,
+        );
+    }
+    playback.shown = 0;
+    playback.needs_reset = false;
+}
+
+fn advance_playback(
+    mut playback: ResMut<ReplayPlayback>,
+    mut tick_events: MessageWriter<TickEvent>,
+    time: Res<Time>,
+) {
+    if !playback.paused {
+        playback.elapsed_ticks += time.delta_secs() * PLAYBACK_TPS * playback.speed;
+        let ticks = playback.elapsed_ticks.floor();
+        playback.elapsed_ticks -= ticks;
+        playback.target = (playback.target + ticks as usize).min(playback.ticks.len());
+    }
+
+    while playback.shown < playback.target {
+        tick_events.write(TickEvent(playback.ticks[playback.shown].clone()));
+        playback.shown += 1;
+    }
+
+    if playback.shown == playback.ticks.len() {
+        playback.paused = true;
+    }
+}
+
+fn update_status(
+    playback: Res<ReplayPlayback>,
+    mut status: Single<&mut TextSpan, With<ReplayStatusMarker>>,
+) {
+    let mut text = format!(
+        "Match {} | Tick {}/{} | {}x",
+        playback.header.match_id,
+        playback.shown,
+        playback.ticks.len(),
+        playback.speed
+    );
+    if playback.paused {
+        text.push_str(" | Paused");
+    }
+    if playback.shown == playback.ticks.len()
+        && let Some(results) = playback
+            .ticks
+            .last()
+            .and_then(|tick| tick.game_results.as_ref())
+    {
+        match results.winner {
+            Some(winner) => text.push_str(&format!(" | Player {} won", winner)),
+            None => text.push_str(&format!(" | {}", results.reason)),
+        }
+    }
+    status.0 = text;
+}
@@ -1,3 +1,5 @@
+use crate::map::MapDef;
+use crate::player::PlayerID;
 use crate::{AppState, AutoDespawn, CollisionLayer};
 use avian3d::{
     parry::{
@@ -10,24 +12,22 @@ use avian3d::{
 use bevy::{
     asset::RenderAssetUsages,
     mesh::{Indices, PrimitiveTopology},
+    pbr::{ExtendedMaterial, MaterialExtension},
     platform::collections::HashMap,
     prelude::*,
+    render::render_resource::AsBindGroup,
+    shader::ShaderRef,
 };
 use serde::{Deserialize, Serialize};
-use std::ops::RangeInclusive;
 
 const CHUNK_WIDTH: usize = 16;
 const CHUNK_HEIGHT: usize = 16;
 const CHUNK_DEPTH: usize = 16;
+const CHUNK_SIZE: IVec3 = IVec3::new(CHUNK_WIDTH as i32, CHUNK_HEIGHT as i32, CHUNK_DEPTH as i32);
 
-// First goal is for player 0, second for player 1
-pub const GOAL_BOUNDS: [(
-    RangeInclusive<i32>,
-    RangeInclusive<i32>,
-    RangeInclusive<i32>,
-); 2] = [(-27..=-25, -3..=-1, -1..=1), (25..=27, -3..=-1, -1..=1)];
-
-#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(
+    Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
 pub enum BlockType {
     #[default]
     Air,
@@ -40,6 +40,15 @@ pub enum BlockType {
 }
 
 impl BlockType {
+    /// The block placed by the player with this ID
+    pub fn team(player_id: PlayerID) -> Self {
+        if player_id.0 == 0 {
+            BlockType::RedBlock
+        } else {
+            BlockType::BlueBlock
+        }
+    }
+
     pub fn get_uvs(&self) -> [(f32, f32, f32, f32); 6] {
         match self {
             BlockType::Air => [(0.0, 0.0, 0.0, 0.0); 6],
@@ -61,6 +70,7 @@ impl BlockType {
 pub struct Chunk {
     blocks: [[[BlockType; CHUNK_HEIGHT]; CHUNK_DEPTH]; CHUNK_WIDTH],
     mesh: Option<Entity>,
+    /// The blocks of the chunk or the blocks next to it changed, so the mesh has to be rebuilt
     dirty: bool,
 }
 
@@ -85,174 +95,247 @@ impl Chunk {
             BlockType::Air
         }
     }
-    pub fn generate_mesh(&self) -> Mesh {
-        let mut mesh = Mesh::new(
-            PrimitiveTopology::TriangleList,
-            RenderAssetUsages::default(),
-        );
+}
 
-        // Typed attribute buffers
-        let mut positions: Vec<[f32; 3]> = Vec::new();
-        let mut normals: Vec<[f32; 3]> = Vec::new();
-        let mut uvs: Vec<[f32; 2]> = Vec::new();
-        let mut indices: Vec<u32> = Vec::new();
+/// One side of a block, in the order of [`BlockType::get_uvs`]
+struct Face {
+    normal: IVec3,
+    /// Corners of the side of a block at the origin, in the order the triangles are wound
+    corners: [IVec3; 4],
+    /// Which texture coordinates of [`BlockType::get_uvs`] every corner uses, `false` for u0 or v0
+    uvs: [(bool, bool); 4],
+    /// The axes the texture's u and v run along
+    u_axis: usize,
+    v_axis: usize,
+}
 
-        // Helper to check if a neighbor is opaque (i.e., inside bounds and not Air)
-        let is_opaque = |x: isize, y: isize, z: isize| -> bool {
-            if x < 0
-                || y < 0
-                || z < 0
-                || x as usize >= CHUNK_WIDTH
-                || y as usize >= CHUNK_HEIGHT
-                || z as usize >= CHUNK_DEPTH
-            {
-                return false; // out of bounds -> treat as Air
-            }
-            self.blocks[x as usize][z as usize][y as usize] != BlockType::Air
-        };
+const FACES: [Face; 6] = [
+    // +X, u along +Z, v along +Y
+    Face {
+        normal: IVec3::X,
+        corners: [
+            IVec3::new(1, 0, 0),
+            IVec3::new(1, 1, 0),
+            IVec3::new(1, 1, 1),
+            IVec3::new(1, 0, 1),
+        ],
+        uvs: [(false, false), (false, true), (true, true), (true, false)],
+        u_axis: 2,
+        v_axis: 1,
+    },
+    // -X, u along -Z, v along +Y
+    Face {
+        normal: IVec3::NEG_X,
+        corners: [
+            IVec3::new(0, 0, 1),
+            IVec3::new(0, 1, 1),
+            IVec3::new(0, 1, 0),
+            IVec3::new(0, 0, 0),
+        ],
+        uvs: [(false, false), (false, true), (true, true), (true, false)],
+        u_axis: 2,
+        v_axis: 1,
+    },
+    // +Y, u along +X, v along +Z
+    Face {
+        normal: IVec3::Y,
+        corners: [
+            IVec3::new(0, 1, 0),
+            IVec3::new(0, 1, 1),
+            IVec3::new(1, 1, 1),
+            IVec3::new(1, 1, 0),
+        ],
+        uvs: [(false, false), (false, true), (true, true), (true, false)],
+        u_axis: 0,
+        v_axis: 2,
+    },
+    // -Y, u along +X, v along +Z
+    Face {
+        normal: IVec3::NEG_Y,
+        corners: [
+            IVec3::new(0, 0, 0),
+            IVec3::new(1, 0, 0),
+            IVec3::new(1, 0, 1),
+            IVec3::new(0, 0, 1),
+        ],
+        uvs: [(false, false), (true, false), (true, true), (false, true)],
+        u_axis: 0,
+        v_axis: 2,
+    },
+    // +Z, u along -X, v along +Y
+    Face {
+        normal: IVec3::Z,
+        corners: [
+            IVec3::new(0, 0, 1),
+            IVec3::new(1, 0, 1),
+            IVec3::new(1, 1, 1),
+            IVec3::new(0, 1, 1),
+        ],
+        uvs: [(true, false), (false, false), (false, true), (true, true)],
+        u_axis: 0,
+        v_axis: 1,
+    },
+    // -Z, u along +X, v along +Y
+    Face {
+        normal: IVec3::NEG_Z,
+        corners: [
+            IVec3::new(1, 0, 0),
+            IVec3::new(0, 0, 0),
+            IVec3::new(0, 1, 0),
+            IVec3::new(1, 1, 0),
+        ],
+        uvs: [(true, false), (false, false), (false, true), (true, true)],
+        u_axis: 0,
+        v_axis: 1,
+    },
+];
 
-        // Per-face UV push (4 verts, 6 indices)
-        let mut push_face =
-            |face_positions: [[f32; 3]; 4], normal: [f32; 3], face_uvs: [[f32; 2]; 4]| {
-                let base = positions.len() as u32;
-                positions.extend_from_slice(&face_positions);
-                normals.extend_from_slice(&[normal; 4]);
-                uvs.extend_from_slice(&face_uvs);
-                indices.extend_from_slice(&[base, base + 1, base + 2, base, base + 2, base + 3]);
-            };
+#[derive(Component, Default)]
+pub struct ChunkMap {
+    chunks: HashMap<IVec3, Chunk>,
+    /// One collider for all the blocks, so players don't catch on the seams between chunks
+    collider: Option<Entity>,
+    /// The voxels of `collider`, `None` when they have to be built from all the blocks
+    shape: Option<SharedShape>,
+    /// Blocks that became solid or air since the collider was last updated
+    changed_voxels: Vec<IVec3>,
+}
 
-        for x in 0..CHUNK_WIDTH {
-            for y in 0..CHUNK_HEIGHT {
-                for z in 0..CHUNK_DEPTH {
-                    let block_type = self.blocks[x][z][y];
-                    if block_type == BlockType::Air {
-                        continue;
-                    }
+/// Only copies the blocks, the copy generates its own meshes and collider
+impl Clone for ChunkMap {
+    fn clone(&self) -> Self {
+        let mut chunk_map = ChunkMap::default();
+        chunk_map.copy_blocks_from(self);
+        chunk_map
+    }
+}
 
-                    let block_uvs = block_type.get_uvs();
-
-                    let xf = x as f32 - 0.5;
-                    let yf = y as f32 - 0.5;
-                    let zf = z as f32 - 0.5;
-
-                    // +X face (right); right = +Z, up = +Y
-                    if !is_opaque(x as isize + 1, y as isize, z as isize) {
-                        let (u0, u1, v0, v1) = block_uvs[0];
-                        push_face(
-                            [
-                                [xf + 1.0, yf + 0.0, zf + 0.0],
-                                [xf + 1.0, yf + 1.0, zf + 0.0],
-                                [xf + 1.0, yf + 1.0, zf + 1.0],
-                                [xf + 1.0, yf + 0.0, zf + 1.0],
-                            ],
-                            [1.0, 0.0, 0.0],
-                            // u along +Z, v along +Y (upright)
-                            [[u0, v0], [u0, v1], [u1, v1], [u1, v0]],
-                        );
-                    }
-                    // -X face (left); right = -Z, up = +Y
-                    if !is_opaque(x as isize - 1, y as isize, z as isize) {
-                        let (u0, u1, v0, v1) = block_uvs[1];
-                        push_face(
-                            [
-                                [xf + 0.0, yf + 0.0, zf + 1.0],
-                                [xf + 0.0, yf + 1.0, zf + 1.0],
-                                [xf + 0.0, yf + 1.0, zf + 0.0],
-                                [xf + 0.0, yf + 0.0, zf + 0.0],
-                            ],
-                            [-1.0, 0.0, 0.0],
-                            // u along -Z, v along +Y (upright)
-                            [[u0, v0], [u0, v1], [u1, v1], [u1, v0]],
-                        );
-                    }
-                    // +Y face (top); right = +X, up = +Z
-                    if !is_opaque(x as isize, y as isize + 1, z as isize) {
-                        let (u0, u1, v0, v1) = block_uvs[2];
-                        push_face(
-                            [
-                                [xf + 0.0, yf + 1.0, zf + 0.0],
-                                [xf + 0.0, yf + 1.0, zf + 1.0],
-                                [xf + 1.0, yf + 1.0, zf + 1.0],
-                                [xf + 1.0, yf + 1.0, zf + 0.0],
-                            ],
-                            [0.0, 1.0, 0.0],
-                            // u along +Z? Choose your preferred top orientation; this keeps it consistent.
-                            [[u0, v0], [u0, v1], [u1, v1], [u1, v0]],
-                        );
-                    }
-                    // -Y face (bottom); right = +X, up = -Z
-                    if !is_opaque(x as isize, y as isize - 1, z as isize) {
-                        let (u0, u1, v0, v1) = block_uvs[3];
-                        push_face(
-                            [
-                                [xf + 0.0, yf + 0.0, zf + 0.0],
-                                [xf + 1.0, yf + 0.0, zf + 0.0],
-                                [xf + 1.0, yf + 0.0, zf + 1.0],
-                                [xf + 0.0, yf + 0.0, zf + 1.0],
-                            ],
-                            [0.0, -1.0, 0.0],
-                            [[u0, v0], [u1, v0], [u1, v1], [u0, v1]],
-                        );
-                    }
-                    // +Z face (front); right = -X, up = +Y
-                    if !is_opaque(x as isize, y as isize, z as isize + 1) {
-                        let (u0, u1, v0, v1) = block_uvs[4];
-                        push_face(
-                            [
-                                [xf + 0.0, yf + 0.0, zf + 1.0],
-                                [xf + 1.0, yf + 0.0, zf + 1.0],
-                                [xf + 1.0, yf + 1.0, zf + 1.0],
-                                [xf + 0.0, yf + 1.0, zf + 1.0],
-                            ],
-                            [0.0, 0.0, 1.0],
-                            // u along -X, v along +Y (upright)
-                            [[u1, v0], [u0, v0], [u0, v1], [u1, v1]],
-                        );
-                    }
-                    // -Z face (back); right = +X, up = +Y
-                    if !is_opaque(x as isize, y as isize, z as isize - 1) {
-                        let (u0, u1, v0, v1) = block_uvs[5];
-                        push_face(
-                            [
-                                [xf + 1.0, yf + 0.0, zf + 0.0],
-                                [xf + 0.0, yf + 0.0, zf + 0.0],
-                                [xf + 0.0, yf + 1.0, zf + 0.0],
-                                [xf + 1.0, yf + 1.0, zf + 0.0],
-                            ],
-                            [0.0, 0.0, -1.0],
-                            // u along +X, v along +Y (upright)
-                            [[u1, v0], [u0, v0], [u0, v1], [u1, v1]],
-                        );
-                    }
+/// How a [`ChunkMap`] is sent over the network and stored in replays
+#[derive(Serialize, Deserialize)]
+struct SerializedChunkMap {
+    chunks: Vec<SerializedChunk>,
+}
+
+/// A chunk as the block types it contains and runs of them, which keeps mostly empty or uniform chunks small
+#[derive(Serialize, Deserialize)]
+struct SerializedChunk {
+    pos: IVec3,
+    /// Every block type in the chunk, once
+    palette: Vec<BlockType>,
+    /// Pairs of a palette index and how many blocks in a row (in storage order) are of that type
+    runs: Vec<(u8, u16)>,
+}
+
+impl SerializedChunk {
+    fn encode(pos: IVec3, chunk: &Chunk) -> Self {
+        let mut palette: Vec<BlockType> = Vec::new();
+        let mut runs: Vec<(u8, u16)> = Vec::new();
+        for block in chunk.blocks.as_flattened().as_flattened() {
+            let index = match palette.iter().position(|b| b == block) {
+                Some(index) => index,
+                None => {
+                    palette.push(*block);
+                    palette.len() - 1
                 }
+            } as u8;
+            match runs.last_mut() {
+                Some((last, count)) if *last == index => *count += 1,
+                _ => runs.push((index, 1)),
             }
         }
+        SerializedChunk { pos, palette, runs }
+    }
 
-        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
-        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
-        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
-        mesh.insert_indices(Indices::U32(indices));
+    fn decode<E: serde::de::Error>(&self) -> Result<Chunk, E> {
+        let mut chunk = Chunk::default();
+        let flat = chunk.blocks.as_flattened_mut().as_flattened_mut();
+        let mut filled = 0;
+        for (index, count) in self.runs.iter() {
+            let Some(block) = self.palette.get(*index as usize) else {
+                return Err(E::custom(format!(
+                    "palette index {} out of range in chunk {}",
+                    index, self.pos
+                )));
+            };
+            let end = filled + *count as usize;
+            if end > flat.len() {
+                return Err(E::invalid_length(end, &"a full chunk of blocks"));
+            }
+            flat[filled..end].fill(*block);
+            filled = end;
+        }
+        if filled != flat.len() {
+            return Err(E::invalid_length(filled, &"a full chunk of blocks"));
+        }
+        Ok(chunk)
+    }
+}
 
-        mesh
+impl Serialize for ChunkMap {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut chunks = self
+            .chunks
+            .iter()
+            .map(|(pos, chunk)| SerializedChunk::encode(*pos, chunk))
+            .collect::<Vec<_>>();
+        // Keep the output the same for the same blocks
+        chunks.sort_by_key(|chunk| chunk.pos.to_array());
+        SerializedChunkMap { chunks }.serialize(serializer)
     }
 }
 
-#[derive(Component, Default)]
-pub struct ChunkMap {
-    chunks: HashMap<IVec3, Chunk>,
-    collider: Option<Entity>,
+impl<'de> Deserialize<'de> for ChunkMap {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let serialized = SerializedChunkMap::deserialize(deserializer)?;
+        let mut chunk_map = ChunkMap::default();
+        for serialized_chunk in serialized.chunks.iter() {
+            chunk_map.insert(serialized_chunk.pos, serialized_chunk.decode()?);
+        }
+        Ok(chunk_map)
+    }
 }
 
 impl ChunkMap {
+    /// The blocks of a map, with empty chunks covering the rest of its build limits
+    pub fn from_map(map: &MapDef) -> Self {
+        let mut chunk_map = ChunkMap::default();
+        let (min_chunk, _) = Self::split_pos(map.build_limits.min);
+        let (max_chunk, _) = Self::split_pos(map.build_limits.max);
+        for x in min_chunk.x..=max_chunk.x {
+            for y in min_chunk.y..=max_chunk.y {
+                for z in min_chunk.z..=max_chunk.z {
+                    chunk_map.insert(IVec3::new(x, y, z), Chunk::default());
+                }
+            }
+        }
+        for fill in map.fills.iter() {
+            for pos in fill.area().positions() {
+                // Maps are validated when they are loaded or generated, so fills are inside the build limits
+                let _ = chunk_map.set_block(pos, fill.block);
+            }
+        }
+        chunk_map
+    }
     pub fn insert(&mut self, pos: IVec3, mut chunk: Chunk) {
         chunk.dirty = true;
         self.chunks.insert(pos, chunk);
+        self.shape = None;
+        for face in FACES.iter() {
+            if let Some(neighbour) = self.chunks.get_mut(&(pos + face.normal)) {
+                neighbour.dirty = true;
+            }
+        }
     }
     pub fn set_block(&mut self, pos: IVec3, block_type: BlockType) -> Result<(), ()> {
         let (chunk_pos, local_pos) = Self::split_pos(pos);
         let Some(chunk) = self.chunks.get_mut(&chunk_pos) else {
             return Err(());
         };
+        let old_block_type = chunk.get_block(
+            local_pos.x as usize,
+            local_pos.y as usize,
+            local_pos.z as usize,
+        );
         if chunk
             .set_block(
                 local_pos.x as usize,
@@ -263,11 +346,97 @@ impl ChunkMap {
             .is_ok()
         {
             chunk.dirty = true;
+            if (old_block_type == BlockType::Air) != (block_type == BlockType::Air) {
+                self.changed_voxels.push(pos);
+            }
+            // Blocks on the border of the chunk are also visible from the chunks next to it
+            for axis in 0..3 {
+                let mut offset = IVec3::ZERO;
+                if local_pos[axis] == 0 {
+                    offset[axis] = -1;
+                } else if local_pos[axis] == CHUNK_SIZE[axis] - 1 {
+                    offset[axis] = 1;
+                } else {
+                    continue;
+                }
+                if let Some(neighbour) = self.chunks.get_mut(&(chunk_pos + offset)) {
+                    neighbour.dirty = true;
+                }
+            }
             Ok(())
         } else {
             Err(())
         }
     }
+    /// Replaces every block with the block from `other`, keeping the existing meshes and collider
+    /// around so they are regenerated instead of leaked
+    pub fn copy_blocks_from(&mut self, other: &ChunkMap) {
+        self.shape = None;
+        for (pos, chunk) in self.chunks.iter_mut() {
+            chunk.blocks = match other.chunks.get(pos) {
+                Some(other_chunk) => other_chunk.blocks,
+                None => Default::default(),
+            };
+            chunk.dirty = true;
+        }
+        for (pos, other_chunk) in other.chunks.iter() {
+            if !self.chunks.contains_key(pos) {
+                self.insert(
+                    *pos,
+                    Chunk {
+                        blocks: other_chunk.blocks,
+                        ..default()
+                    },
+                );
+            }
+        }
+    }
+    /// The blocks that differ from `other`, with the block `other` has there.
+    /// Applying them with [`ChunkMap::apply_diff`] makes this map's blocks the same as `other`'s.
+    pub fn diff(&self, other: &ChunkMap) -> Vec<(IVec3, BlockType)> {
+        let empty = Chunk::default();
+        let mut positions = self
+            .chunks
+            .keys()
+            .chain(other.chunks.keys())
+            .copied()
+            .collect::<Vec<_>>();
+        positions.sort_by_key(|pos| pos.to_array());
+        positions.dedup();
+
+        let mut changes = Vec::new();
+        for chunk_pos in positions {
+            let ours = self.chunks.get(&chunk_pos).unwrap_or(&empty);
+            let theirs = other.chunks.get(&chunk_pos).unwrap_or(&empty);
+            if ours.blocks == theirs.blocks {
+                continue;
+            }
+            let origin = chunk_pos * CHUNK_SIZE;
+            for x in 0..CHUNK_WIDTH {
+                for z in 0..CHUNK_DEPTH {
+                    for y in 0..CHUNK_HEIGHT {
+                        let block = theirs.blocks[x][z][y];
+                        if ours.blocks[x][z][y] != block {
+                            let pos = origin + IVec3::new(x as i32, y as i32, z as i32);
+                            changes.push((pos, block));
+                        }
+                    }
+                }
+            }
+        }
+        changes
+    }
+    /// Sets every block in the list, adding the chunks that don't exist yet.
+    /// Only the chunks that changed have their meshes regenerated.
+    pub fn apply_diff(&mut self, changes: &[(IVec3, BlockType)]) {
+        for (pos, block_type) in changes.iter() {
+            if self.set_block(*pos, *block_type).is_err() {
+                let (chunk_pos, _) = Self::split_pos(*pos);
+                self.insert(chunk_pos, Chunk::default());
+                let _ = self.set_block(*pos, *block_type);
+            }
+        }
+    }
     pub fn get_block(&self, pos: IVec3) -> BlockType {
         let (chunk_pos, local_pos) = Self::split_pos(pos);
         if let Some(chunk) = self.chunks.get(&chunk_pos) {
@@ -279,10 +448,232 @@ impl ChunkMap {
         }
         BlockType::Air
     }
+    /// Builds the mesh of the chunk at `chunk_pos`. Faces covered by a block are left out, also when the
+    /// block is in a neighbouring chunk, and neighbouring faces with the same texture are merged into one quad.
+    ///
+    /// `UV_0` counts blocks across a quad and `UV_1` is the corner of the block's tile in the atlas,
+    /// so the atlas material can repeat the tile across merged faces.
+    pub fn generate_mesh(&self, chunk_pos: IVec3) -> Mesh {
+        let mut mesh = Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::default(),
+        );
+
+        let mut positions: Vec<[f32; 3]> = Vec::new();
+        let mut normals: Vec<[f32; 3]> = Vec::new();
+        let mut uvs: Vec<[f32; 2]> = Vec::new();
+        let mut tiles: Vec<[f32; 2]> = Vec::new();
+        let mut indices: Vec<u32> = Vec::new();
+
+        let chunk = self.chunks.get(&chunk_pos);
+        let origin = chunk_pos * CHUNK_SIZE;
+        let block_at = |local_pos: IVec3| -> BlockType {
+            if local_pos.cmpge(IVec3::ZERO).all() && local_pos.cmplt(CHUNK_SIZE).all() {
+                chunk.map_or(BlockType::Air, |chunk| {
+                    chunk.get_block(
+                        local_pos.x as usize,
+                        local_pos.y as usize,
+                        local_pos.z as usize,
+                    )
+                })
+            } else {
+                self.get_block(origin + local_pos)
+            }
+        };
+
+        for (face_index, face) in FACES.iter().enumerate() {
+            let normal_axis = 3 - face.u_axis - face.v_axis;
+            let (u_len, v_len) = (CHUNK_SIZE[face.u_axis], CHUNK_SIZE[face.v_axis]);
+            let index = |u: i32, v: i32| (u * v_len + v) as usize;
+            for layer in 0..CHUNK_SIZE[normal_axis] {
+                // The block of every visible face in the layer, air where there is none
+                let mut visible = vec![BlockType::Air; (u_len * v_len) as usize];
+                for u in 0..u_len {
+                    for v in 0..v_len {
+                        let mut local_pos = IVec3::ZERO;
+                        local_pos[normal_axis] = layer;
+                        local_pos[face.u_axis] = u;
+                        local_pos[face.v_axis] = v;
+                        let block_type = block_at(local_pos);
+                        if block_type != BlockType::Air
+                            && block_at(local_pos + face.normal) == BlockType::Air
+                        {
+                            visible[index(u, v)] = block_type;
+                        }
+                    }
+                }
+
+                // Grow every face as far as possible along u, then along v
+                for v in 0..v_len {
+                    for u in 0..u_len {
+                        let block_type = visible[index(u, v)];
+                        if block_type == BlockType::Air {
+                            continue;
+                        }
+                        let mut width = 1;
+                        while u + width < u_len && visible[index(u + width, v)] == block_type {
+                            width += 1;
+                        }
+                        let mut height = 1;
+                        while v + height < v_len
+                            && (u..u + width).all(|i| visible[index(i, v + height)] == block_type)
+                        {
+                            height += 1;
+                        }
+                        for i in u..u + width {
+                            for j in v..v + height {
+                                visible[index(i, j)] = BlockType::Air;
+                            }
+                        }
+
+                        let mut start = IVec3::ZERO;
+                        start[normal_axis] = layer;
+                        start[face.u_axis] = u;
+                        start[face.v_axis] = v;
+                        let mut size = IVec3::ONE;
+                        size[face.u_axis] = width;
+                        size[face.v_axis] = height;
+
+                        let (u0, u1, v0, v1) = block_type.get_uvs()[face_index];
+                        let tile = [u0.min(u1), v0.min(v1)];
+                        let base = positions.len() as u32;
+                        for (corner, (end_u, end_v)) in face.corners.iter().zip(face.uvs) {
+                            let position = (start + *corner * size).as_vec3() - Vec3::splat(0.5);
+                            positions.push(position.to_array());
+                            normals.push(face.normal.as_vec3().to_array());
+                            // Flipped textures count down from the far side of the quad
+                            let atlas_u = if end_u { u1 } else { u0 };
+                            let atlas_v = if end_v { v1 } else { v0 };
+                            let local_u = if atlas_u == tile[0] { 0 } else { width };
+                            let local_v = if atlas_v == tile[1] { 0 } else { height };
+                            uvs.push([local_u as f32, local_v as f32]);
+                            tiles.push(tile);
+                        }
+                        indices.extend_from_slice(&[
+                            base,
+                            base + 1,
+                            base + 2,
+                            base,
+                            base + 2,
+                            base + 3,
+                        ]);
+                    }
+                }
+            }
+        }
+
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_1, tiles);
+        mesh.insert_indices(Indices::U32(indices));
+
+        mesh
+    }
+    /// Brings the collider up to date with the blocks, see [`ChunkMap::update_voxels`]
+    fn update_collider(&mut self, colliders: &mut Query<&mut Collider>, commands: &mut Commands) {
+        if self.shape.is_some() && self.changed_voxels.is_empty() {
+            return;
+        }
+        let mut collider = self
+            .collider
+            .and_then(|entity| colliders.get_mut(entity).ok());
+        if let Some(collider) = collider.as_mut() {
+            // Let go of the collider's reference to the voxels, so they are changed in place instead of copied
+            **collider = Collider::sphere(0.5);
+        }
+        self.update_voxels();
+
+        let Some(shape) = self.shape.clone() else {
+            if let Some(collider_entity) = self.collider.take() {
+                commands.entity(collider_entity).despawn();
+            }
+            return;
+        };
+        match (collider, self.collider) {
+            (Some(mut collider), _) => *collider = Collider::from(shape),
+            // Spawned this frame, so it isn't in the query yet
+            (None, Some(collider_entity)) => {
+                commands
+                    .entity(collider_entity)
+                    .insert(Collider::from(shape));
+            }
+            (None, None) => {
+                let collider_entity = commands
+                    .spawn((
+                        RigidBody::Static,
+                        Collider::from(shape),
+                        CollisionLayers::new(
+                            CollisionLayer::World,
+                            [CollisionLayer::Player, CollisionLayer::Projectile],
+                        ),
+                        Friction::new(0.0),
+                        Restitution::new(0.0),
+                        Transform::default(),
+                        AutoDespawn(AppState::Game),
+                    ))
+                    .id();
+                self.collider = Some(collider_entity);
+            }
+        }
+    }
+    /// Brings the voxels of the collider up to date with the blocks. Only the voxels of the blocks that
+    /// changed are touched, unless the voxels have to be built from all the blocks.
+    fn update_voxels(&mut self) {
+        let changes = std::mem::take(&mut self.changed_voxels)
+            .into_iter()
+            .map(|pos| (pos, self.get_block(pos) != BlockType::Air))
+            .collect::<Vec<_>>();
+        if let Some(shape) = self.shape.as_mut() {
+            // Copies the voxels only if something else still holds on to them
+            let voxels = shape
+                .make_mut()
+                .as_shape_mut::<Voxels>()
+                .expect("The world collider is made of voxels");
+            for (pos, filled) in changes {
+                voxels.set_voxel(Point::new(pos.x, pos.y, pos.z), filled);
+            }
+            return;
+        }
+
+        let mut points: Vec<Point<i32>> = Vec::new();
+        for (chunk_pos, chunk) in self.chunks.iter() {
+            let origin = *chunk_pos * CHUNK_SIZE;
+            for i in 0..CHUNK_WIDTH {
+                for j in 0..CHUNK_HEIGHT {
+                    for k in 0..CHUNK_DEPTH {
+                        if chunk.blocks[i][k][j] != BlockType::Air {
+                            points.push(Point::new(
+                                origin.x + i as i32,
+                                origin.y + j as i32,
+                                origin.z + k as i32,
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        if points.is_empty() {
+            return;
+        }
+        let block_shape = 1.0;
+        let mut voxels = Voxels::new(Vector3::new(block_shape, block_shape, block_shape), &points);
+        // Blocks can only be set inside the chunks, so the voxels cover all of them and never have to grow
+        let (mut min, mut max) = (IVec3::MAX, IVec3::MIN);
+        for chunk_pos in self.chunks.keys() {
+            min = min.min(*chunk_pos);
+            max = max.max(*chunk_pos);
+        }
+        let (min, max) = (min * CHUNK_SIZE, (max + IVec3::ONE) * CHUNK_SIZE);
+        voxels.resize_domain(
+            Point::new(min.x, min.y, min.z),
+            Point::new(max.x, max.y, max.z),
+        );
+        self.shape = Some(SharedShape::new(voxels));
+    }
     fn split_pos(pos: IVec3) -> (IVec3, IVec3) {
-        let chunk_size = IVec3::new(CHUNK_WIDTH as i32, CHUNK_HEIGHT as i32, CHUNK_DEPTH as i32);
-        let chunk_pos = pos.div_euclid(chunk_size);
-        let local_pos = pos.rem_euclid(chunk_size);
+        let chunk_pos = pos.div_euclid(CHUNK_SIZE);
+        let local_pos = pos.rem_euclid(CHUNK_SIZE);
         (chunk_pos, local_pos)
     }
 }
@@ -300,166 +691,313 @@ impl WorldPlugin {
 impl Plugin for WorldPlugin {
     fn build(&self, app: &mut App) {
         if !self.headless {
-            app.add_systems(Startup, setup);
+            app.add_plugins(MaterialPlugin::<ChunkMaterial>::default())
+                .add_systems(Startup, setup);
         }
         app.add_systems(Update, regen_dirty_chunks);
     }
 }
 
+/// The atlas with its tiles repeated across the merged faces of chunk meshes
+type ChunkMaterial = ExtendedMaterial<StandardMaterial, AtlasTiling>;
+
+/// Maps the block counts in `UV_0` of a chunk mesh into the atlas tile at `UV_1`, see [`ChunkMap::generate_mesh`]
+#[derive(Asset, AsBindGroup, Reflect, Debug, Clone, Default)]
+struct AtlasTiling {}
+
+impl MaterialExtension for AtlasTiling {
+    fn fragment_shader() -> ShaderRef {
+        "shaders/chunk.wgsl".into()
+    }
+}
+
 #[derive(Resource)]
 struct WorldPluginData {
-    atlas_material: Handle<StandardMaterial>,
+    atlas_material: Handle<ChunkMaterial>,
 }
 
 fn setup(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut materials: ResMut<Assets<ChunkMaterial>>,
 ) {
     commands.insert_resource(WorldPluginData {
-        atlas_material: materials.add(StandardMaterial {
-            base_color_texture: Some(asset_server.load("textures/atlas.png")),
-            reflectance: 0.0,
-            perceptual_roughness: 1.0,
-            ..default()
+        atlas_material: materials.add(ExtendedMaterial {
+            base: StandardMaterial {
+                base_color_texture: Some(asset_server.load("textures/atlas.png")),
+                reflectance: 0.0,
+                perceptual_roughness: 1.0,
+                ..default()
+            },
+            extension: AtlasTiling {},
         }),
     });
 }
 
+/// Updates the collider and rebuilds the mesh of every chunk that changed or had a block next to it change,
+/// the other chunks keep theirs
 fn regen_dirty_chunks(
     mut commands: Commands,
     data: Option<Res<WorldPluginData>>,
     mut meshes: Option<ResMut<Assets<Mesh>>>,
     mut chunk_map: Single<&mut ChunkMap, Changed<ChunkMap>>,
+    mut colliders: Query<&mut Collider>,
 ) {
-    let mut dirty = false;
-    for (pos, chunk) in chunk_map.chunks.iter_mut() {
-        if chunk.dirty {
-            dirty = true;
-            if data.is_none() || meshes.is_none() {
-                chunk.dirty = false;
-                continue;
-            }
-            if let Some(mesh_entity) = chunk.mesh {
-                commands.entity(mesh_entity).despawn();
-            }
-            let mesh = chunk.generate_mesh();
-            if mesh.count_vertices() == 0 {
-                chunk.mesh = None;
-                continue;
-            } else {
-                let mesh_entity = commands
-                    .spawn((
-                        Mesh3d(meshes.as_mut().unwrap().add(mesh)),
-                        MeshMaterial3d(data.as_ref().unwrap().atlas_material.clone()),
-                        Transform::default()
-                            .with_translation(pos.as_vec3() * 16.0 + Vec3::splat(0.5)),
-                        AutoDespawn(AppState::Game),
-                    ))
-                    .id();
-                chunk.mesh = Some(mesh_entity);
-            }
-            // Reset dirty flag after regenerating
-            chunk.dirty = false;
+    let chunk_map: &mut ChunkMap = &mut chunk_map;
+    chunk_map.update_collider(&mut colliders, &mut commands);
+
+    let dirty_chunks = chunk_map
+        .chunks
+        .iter()
+        .filter(|(_, chunk)| chunk.dirty)
+        .map(|(pos, _)| *pos)
+        .collect::<Vec<_>>();
+    for pos in dirty_chunks {
+        // Built before borrowing the chunk, as the mesh looks into the neighbouring chunks
+        let mesh = (data.is_some() && meshes.is_some()).then(|| chunk_map.generate_mesh(pos));
+        let Some(chunk) = chunk_map.chunks.get_mut(&pos) else {
+            continue;
+        };
+        chunk.dirty = false;
+
+        let (Some(data), Some(meshes), Some(mesh)) = (data.as_ref(), meshes.as_mut(), mesh) else {
+            continue;
+        };
+        if let Some(mesh_entity) = chunk.mesh.take() {
+            commands.entity(mesh_entity).despawn();
+        }
+        if mesh.count_vertices() > 0 {
+            let mesh_entity = commands
+                .spawn((
+                    Mesh3d(meshes.add(mesh)),
+                    MeshMaterial3d(data.atlas_material.clone()),
+                    Transform::default()
+                        .with_translation(pos.as_vec3() * 16.0 + Vec3::splat(0.5)),
+                    AutoDespawn(AppState::Game),
+                ))
+                .id();
+            chunk.mesh = Some(mesh_entity);
         }
     }
+}
 
-    if dirty {
-        if let Some(collider_entity) = chunk_map.collider {
-            commands.entity(collider_entity).despawn();
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use avian3d::parry::query::PointQuery;
+    use bevy::mesh::VertexAttributeValues;
+    use serde::de::value::Error as DeError;
 
-        let mut points: Vec<Point<i32>> = Vec::new();
-        for (pos, chunk) in chunk_map.chunks.iter() {
-            for i in 0..CHUNK_WIDTH {
-                for j in 0..CHUNK_HEIGHT {
-                    for k in 0..CHUNK_DEPTH {
-                        if chunk.blocks[i][k][j] != BlockType::Air {
-                            points.push(Point::new(
-                                i as i32 + pos.x * CHUNK_WIDTH as i32,
-                                j as i32 + pos.y * CHUNK_HEIGHT as i32,
-                                k as i32 + pos.z * CHUNK_DEPTH as i32,
-                            ));
-                        }
-                    }
-                }
+    fn mixed_chunk() -> Chunk {
+        let mut chunk = Chunk::default();
+        for x in 0..CHUNK_WIDTH {
+            for z in 0..CHUNK_DEPTH {
+                chunk.set_block(x, 0, z, BlockType::Stone).unwrap();
+                chunk.set_block(x, 1, z, BlockType::Dirt).unwrap();
             }
         }
+        chunk.set_block(3, 2, 4, BlockType::Grass).unwrap();
+        chunk.set_block(15, 15, 15, BlockType::RedBlock).unwrap();
+        chunk.set_block(0, 7, 9, BlockType::BlueBlock).unwrap();
+        chunk
+    }
 
-        let block_shape = 1.0;
-        let voxel_shape = Voxels::new(Vector3::new(block_shape, block_shape, block_shape), &points);
-        let collider_entity = commands
-            .spawn((
-                RigidBody::Static,
-                Collider::from(SharedShape::new(voxel_shape)),
-                CollisionLayers::new(
-                    CollisionLayer::World,
-                    [CollisionLayer::Player, CollisionLayer::Projectile],
-                ),
-                Friction::new(0.0),
-                Restitution::new(0.0),
-                Transform::from_xyz(0.0, 0.0, 0.0),
-                AutoDespawn(AppState::Game),
-            ))
-            .id();
-        chunk_map.collider = Some(collider_entity);
+    fn uniform_chunk(block_type: BlockType) -> Chunk {
+        Chunk {
+            blocks: [[[block_type; CHUNK_HEIGHT]; CHUNK_DEPTH]; CHUNK_WIDTH],
+            ..default()
+        }
     }
-}
 
-pub fn init_map() -> ChunkMap {
-    let mut chunkmap = ChunkMap::default();
+    fn round_trip(chunk: &Chunk) -> Chunk {
+        SerializedChunk::encode(IVec3::new(1, -2, 3), chunk)
+            .decode::<DeError>()
+            .unwrap()
+    }
+
+    #[test]
+    fn encoded_chunks_decode_to_the_same_blocks() {
+        let empty = Chunk::default();
+        let encoded = SerializedChunk::encode(IVec3::ZERO, &empty);
+        assert_eq!(encoded.palette, vec![BlockType::Air]);
+        assert_eq!(encoded.runs.len(), 1);
+        assert!(round_trip(&empty).blocks == empty.blocks);
+
+        let stone = uniform_chunk(BlockType::Stone);
+        assert!(round_trip(&stone).blocks == stone.blocks);
+
+        let mixed = mixed_chunk();
+        assert!(round_trip(&mixed).blocks == mixed.blocks);
+    }
+
+    #[test]
+    fn chunk_maps_survive_postcard() {
+        let mut chunk_map = ChunkMap::default();
+        chunk_map.insert(IVec3::ZERO, mixed_chunk());
+        chunk_map.insert(IVec3::new(-1, 0, 2), uniform_chunk(BlockType::WhiteBlock));
+        chunk_map.insert(IVec3::new(0, 1, 0), Chunk::default());
+
+        let bytes = postcard::to_allocvec(&chunk_map).unwrap();
+        let decoded: ChunkMap = postcard::from_bytes(&bytes).unwrap();
+        assert!(chunk_map.diff(&decoded).is_empty());
+        assert_eq!(decoded.chunks.len(), chunk_map.chunks.len());
+    }
+
+    #[test]
+    fn decode_rejects_bad_chunks() {
+        let full = (CHUNK_WIDTH * CHUNK_HEIGHT * CHUNK_DEPTH) as u16;
+        let chunk = |palette: Vec<BlockType>, runs: Vec<(u8, u16)>| SerializedChunk {
+            pos: IVec3::ZERO,
+            palette,
+            runs,
+        };
+
+        assert!(
+            chunk(vec![BlockType::Air], vec![(1, full)])
+                .decode::<DeError>()
+                .is_err()
+        );
+        assert!(
+            chunk(vec![BlockType::Air], vec![(0, full - 1)])
+                .decode::<DeError>()
+                .is_err()
+        );
+        assert!(
+            chunk(vec![BlockType::Air], vec![(0, full), (0, 1)])
+                .decode::<DeError>()
+                .is_err()
+        );
+        assert!(
+            chunk(vec![BlockType::Air], vec![])
+                .decode::<DeError>()
+                .is_err()
+        );
+        assert!(
+            chunk(vec![BlockType::Air], vec![(0, full)])
+                .decode::<DeError>()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn applying_a_diff_makes_the_maps_equal() {
+        let mut a = ChunkMap::default();
+        a.insert(IVec3::ZERO, mixed_chunk());
+        a.insert(IVec3::new(1, 0, 0), uniform_chunk(BlockType::Stone));
+        a.insert(IVec3::new(0, 0, 1), uniform_chunk(BlockType::Dirt));
+
+        let mut b = ChunkMap::default();
+        let mut changed = mixed_chunk();
+        changed.set_block(3, 2, 4, BlockType::Air).unwrap();
+        changed.set_block(8, 8, 8, BlockType::RedBlock).unwrap();
+        b.insert(IVec3::ZERO, changed);
+        b.insert(IVec3::new(1, 0, 0), uniform_chunk(BlockType::Stone));
+        // Only in b
+        b.insert(IVec3::new(-1, -1, -1), mixed_chunk());
 
-    for x in -2..=2 {
-        for y in -1..=1 {
-            for z in -1..=1 {
-                chunkmap.insert((x, y, z).into(), Chunk::default());
+        let diff = a.diff(&b);
+        assert!(diff.contains(&(IVec3::new(3, 2, 4), BlockType::Air)));
+        assert!(diff.contains(&(IVec3::new(8, 8, 8), BlockType::RedBlock)));
+
+        a.apply_diff(&diff);
+        assert!(a.diff(&b).is_empty());
+        assert!(b.diff(&a).is_empty());
+        assert!(a.chunks.contains_key(&IVec3::new(-1, -1, -1)));
+        // The last block of chunk (-1, -1, -1)
+        assert_eq!(a.get_block(IVec3::new(-1, -1, -1)), BlockType::RedBlock);
+        // Chunks only in a are emptied
+        assert_eq!(a.get_block(IVec3::new(0, 0, 16)), BlockType::Air);
+    }
+
+    /// How many quads of the mesh face along the normal
+    fn quads_facing(mesh: &Mesh, normal: IVec3) -> usize {
+        let Some(VertexAttributeValues::Float32x3(normals)) =
+            mesh.attribute(Mesh::ATTRIBUTE_NORMAL)
+        else {
+            panic!("Chunk meshes have normals");
+        };
+        let normal = normal.as_vec3().to_array();
+        normals.iter().filter(|n| **n == normal).count() / 4
+    }
+
+    #[test]
+    fn a_slab_merges_into_one_quad_per_face() {
+        let mut slab = Chunk::default();
+        for x in 0..CHUNK_WIDTH {
+            for z in 0..CHUNK_DEPTH {
+                slab.set_block(x, 0, z, BlockType::Stone).unwrap();
             }
         }
-    }
+        let mut chunk_map = ChunkMap::default();
+        chunk_map.insert(IVec3::ZERO, slab);
 
-    for x in -20..=20 {
-        for y in -8..=0 {
-            chunkmap
-                .set_block(
-                    (x, y, 0).into(),
-                    match x {
-                        -20..0 => BlockType::BlueBlock,
-                        0 => BlockType::WhiteBlock,
-                        1..=20 => BlockType::RedBlock,
-                        _ => unreachable!(),
-                    },
-                )
-                .unwrap();
+        let mesh = chunk_map.generate_mesh(IVec3::ZERO);
+        for face in FACES.iter() {
+            assert_eq!(quads_facing(&mesh, face.normal), 1);
         }
+        assert_eq!(mesh.count_vertices(), 6 * 4);
     }
 
-    for i in 0..2 {
-        for x in 21..=30 {
-            for y in -5..=0 {
-                'outer: for z in -5..=5 {
-                    for (x_range, y_range, z_range) in GOAL_BOUNDS.iter() {
-                        if x_range.contains(&x)
-                            && (*y_range.start()..=y_range.end() + 1).contains(&y)
-                            && z_range.contains(&z)
-                        {
-                            continue 'outer;
-                        }
-                    }
-                    chunkmap
-                        .set_block(
-                            (x * (i * 2 - 1), y, z).into(),
-                            match y {
-                                -5..=-3 => BlockType::Stone,
-                                -2..=-1 => BlockType::Dirt,
-                                0 => BlockType::Grass,
-                                _ => unreachable!(),
-                            },
-                        )
-                        .unwrap();
-                }
+    #[test]
+    fn faces_against_solid_chunks_are_culled() {
+        let mut chunk_map = ChunkMap::default();
+        chunk_map.insert(IVec3::ZERO, uniform_chunk(BlockType::Stone));
+        chunk_map.insert(IVec3::X, uniform_chunk(BlockType::Dirt));
+
+        let mesh = chunk_map.generate_mesh(IVec3::ZERO);
+        assert_eq!(quads_facing(&mesh, IVec3::X), 0);
+        assert_eq!(quads_facing(&mesh, IVec3::NEG_X), 1);
+        let neighbour_mesh = chunk_map.generate_mesh(IVec3::X);
+        assert_eq!(quads_facing(&neighbour_mesh, IVec3::NEG_X), 0);
+    }
+
+    #[test]
+    fn faces_against_air_chunks_are_kept() {
+        let mut chunk_map = ChunkMap::default();
+        chunk_map.insert(IVec3::ZERO, uniform_chunk(BlockType::Stone));
+        chunk_map.insert(IVec3::X, Chunk::default());
+
+        let mesh = chunk_map.generate_mesh(IVec3::ZERO);
+        assert_eq!(quads_facing(&mesh, IVec3::X), 1);
+        assert_eq!(chunk_map.generate_mesh(IVec3::X).count_vertices(), 0);
+    }
+
+    #[test]
+    fn blocks_placed_away_from_the_others_become_solid() {
+        let is_solid = |chunk_map: &ChunkMap, pos: IVec3| {
+            let point = pos.as_vec3() + Vec3::splat(0.5);
+            chunk_map
+                .shape
+                .as_ref()
+                .unwrap()
+                .contains_local_point(&Point::new(point.x, point.y, point.z))
+        };
+        let mut floor = Chunk::default();
+        for x in 0..CHUNK_WIDTH {
+            for z in 0..CHUNK_DEPTH {
+                floor.set_block(x, 0, z, BlockType::Stone).unwrap();
             }
         }
-    }
+        let mut chunk_map = ChunkMap::default();
+        chunk_map.insert(IVec3::ZERO, floor);
+        chunk_map.insert(IVec3::new(0, 1, 0), Chunk::default());
+        chunk_map.insert(IVec3::new(1, 0, 0), Chunk::default());
+        chunk_map.update_voxels();
+        assert!(is_solid(&chunk_map, IVec3::new(3, 0, 3)));
 
-    chunkmap
+        // Above and beside the blocks the voxels were built from
+        let above = IVec3::new(3, 20, 3);
+        let beside = IVec3::new(20, 5, 2);
+        assert!(!is_solid(&chunk_map, above));
+        assert!(!is_solid(&chunk_map, beside));
+        chunk_map.set_block(above, BlockType::RedBlock).unwrap();
+        chunk_map.set_block(beside, BlockType::BlueBlock).unwrap();
+        chunk_map
+            .set_block(IVec3::new(3, 0, 3), BlockType::Air)
+            .unwrap();
+        chunk_map.update_voxels();
+        assert!(is_solid(&chunk_map, above));
+        assert!(is_solid(&chunk_map, beside));
+        assert!(!is_solid(&chunk_map, IVec3::new(3, 0, 3)));
+    }
 }
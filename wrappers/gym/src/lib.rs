@@ -0,0 +1,343 @@
+use agentduels::config::MatchConfig;
+use agentduels::player::{Inventory, Item, PlayerActions, Rotation};
+use agentduels::sim::Match;
+use agentduels::{DamageSource, TickMessage};
+use bevy::math::IVec3;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+
+/// Boolean action keys and the action flags they set
+const ACTION_FLAGS: [(&str, u16); 11] = [
+    ("move_forward", PlayerActions::MOVE_FORWARD),
+    ("move_backward", PlayerActions::MOVE_BACKWARD),
+    ("move_left", PlayerActions::MOVE_LEFT),
+    ("move_right", PlayerActions::MOVE_RIGHT),
+    ("jump", PlayerActions::JUMP),
+    ("attack", PlayerActions::ATTACK),
+    ("use_item", PlayerActions::USE_ITEM),
+    ("place_block", PlayerActions::PLACE_BLOCK),
+    ("dig_block", PlayerActions::DIG_BLOCK),
+    ("sprint", PlayerActions::SPRINT),
+    ("sneak", PlayerActions::SNEAK),
+];
+
+/// What a player looks like between ticks, since ticks only contain health and inventory changes
+struct PlayerState {
+    health: f32,
+    inventory: Inventory,
+    score: u16,
+}
+
+impl PlayerState {
+    fn new(config: &MatchConfig) -> Self {
+        PlayerState {
+            health: config.max_health,
+            inventory: Inventory::new(config.starting_kit.clone()),
+            score: 0,
+        }
+    }
+}
+
+/// A match against an opponent, simulated in-process.
+///
+/// `reset()` starts a new match and `step(action, opponent_action=None)` advances it by one tick,
+/// following the Gymnasium API. Actions are dicts with any of the keys `move_forward`, `move_backward`,
+/// `move_left`, `move_right`, `jump`, `attack`, `use_item`, `place_block`, `dig_block`, `sprint`, `sneak` (booleans),
+/// `yaw`, `pitch` (radians, 0 faces the opponent's side) and `select_item` (an item name).
+/// Missing keys do nothing, and `None` is an idle action. Sprinting only works while moving forward,
+/// and sneaking takes precedence over it.
+///
+/// The `stance` of each player in the observation is `Standing`, `Sprinting` or `Sneaking`.
+/// `hits` lists the melee hits of the tick with their `damage`, `knockback` and whether they were `critical`,
+/// and `damage` every time a player lost health, with the `source` (`Melee`, `Arrow`, `Fall` or `Void`)
+/// and the `attacker` for melee and arrow damage.
+///
+/// The reward is 1 when the agent scores a goal and -1 when the opponent does.
+#[pyclass(unsendable)]
+struct DuelEnv {
+    config: MatchConfig,
+    player_id: usize,
+    sim: Option<Match>,
+    players: [PlayerState; 2],
+    finished: bool,
+}
+
+#[pymethods]
+impl DuelEnv {
+    /// `config` takes the same keys as the `[match]` table of the server config
+    #[new]
+    #[pyo3(signature = (config=None, player_id=0))]
+    fn new(py: Python<'_>, config: Option<Bound<'_, PyDict>>, player_id: usize) -> PyResult<Self> {
+        if player_id > 1 {
+            return Err(PyValueError::new_err("player_id must be 0 or 1"));
+        }
+        let config: MatchConfig = match config {
+            Some(config) => {
+                let json = py
+                    .import("json")?
+                    .call_method1("dumps", (config,))?
+                    .extract::<String>()?;
+                serde_json::from_str(&json)
+                    .map_err(|e| PyValueError::new_err(format!("Invalid match config: {}", e)))?
+            }
+            None => MatchConfig::default(),
+        };
+        config
+            .map
+            .validate()
+            .map_err(|e| PyValueError::new_err(format!("Invalid map: {:#}", e)))?;
+
+        Ok(DuelEnv {
+            players: [PlayerState::new(&config), PlayerState::new(&config)],
+            config,
+            player_id,
+            sim: None,
+            finished: false,
+        })
+    }
+
+    /// Starts a new match and returns the observation of its first tick along with an info dict.
+    ///
+    /// Maps are generated here, and a generator that fails with the seed raises a `ValueError`.
+    #[pyo3(signature = (*, seed=None))]
+    fn reset<'py>(
+        &mut self,
+        py: Python<'py>,
+        seed: Option<u64>,
+    ) -> PyResult<(Bound<'py, PyDict>, Bound<'py, PyDict>)> {
+        let mut config = self.config.clone();
+        let seed = match seed.or(config.seed) {
+            Some(seed) => seed,
+            None => py
+                .import("random")?
+                .call_method1("getrandbits", (64,))?
+                .extract::<u64>()?,
+        };
+        config.seed = Some(seed);
+        // Generated here so a seed the generator fails on is an error instead of a panic in the match
+        config
+            .generate_map(seed)
+            .map_err(|e| PyValueError::new_err(format!("Invalid map generator: {:#}", e)))?;
+        self.players = [PlayerState::new(&config), PlayerState::new(&config)];
+        self.finished = false;
+
+        let mut sim = Match::new(config);
+        let tick = sim.step([PlayerActions::default(); 2]);
+        self.sim = Some(sim);
+        self.update_players(&tick);
+
+        Ok((self.observation(py, &tick)?, self.info(py, &tick)?))
+    }
+
+    /// Advances the match by one tick.
+    /// Returns `(observation, reward, terminated, truncated, info)`, the info has the `winner` and `reason` once the match is over.
+    #[pyo3(signature = (action=None, opponent_action=None))]
+    fn step<'py>(
+        &mut self,
+        py: Python<'py>,
+        action: Option<Bound<'py, PyAny>>,
+        opponent_action: Option<Bound<'py, PyAny>>,
+    ) -> PyResult<(Bound<'py, PyDict>, f32, bool, bool, Bound<'py, PyDict>)> {
+        if self.finished {
+            return Err(PyRuntimeError::new_err(
+                "The match is over, call reset() to start a new one",
+            ));
+        }
+        let Some(sim) = self.sim.as_mut() else {
+            return Err(PyRuntimeError::new_err("Call reset() before step()"));
+        };
+
+        let mut actions = [PlayerActions::default(); 2];
+        actions[self.player_id] = parse_action(action)?;
+        actions[self.player_id ^ 1] = parse_action(opponent_action)?;
+        let tick = sim.step(actions);
+        self.update_players(&tick);
+
+        let reward = match tick.goals {
+            Some(scorer) if scorer.0 as usize == self.player_id => 1.0,
+            Some(_) => -1.0,
+            None => 0.0,
+        };
+        self.finished = tick.game_results.is_some();
+
+        Ok((
+            self.observation(py, &tick)?,
+            reward,
+            self.finished,
+            false,
+            self.info(py, &tick)?,
+        ))
+    }
+
+    /// The name of the block at the given position in the current match
+    fn get_block(&mut self, x: i32, y: i32, z: i32) -> PyResult<String> {
+        let Some(sim) = self.sim.as_mut() else {
+            return Err(PyRuntimeError::new_err("Call reset() before get_block()"));
+        };
+        Ok(format!("{:?}", sim.get_block(IVec3::new(x, y, z))))
+    }
+
+    #[getter]
+    fn player_id(&self) -> usize {
+        self.player_id
+    }
+
+    fn close(&mut self) {
+        self.sim = None;
+    }
+}
+
+impl DuelEnv {
+    fn update_players(&mut self, tick: &TickMessage) {
+        for (state, info) in self.players.iter_mut().zip(tick.players.iter()) {
+            if let Some(health) = info.health_update {
+                state.health = health;
+            }
+            if let Some(inventory) = &info.inventory_update {
+                state.inventory = inventory.clone();
+            }
+        }
+        if let Some(scorer) = tick.goals {
+            self.players[scorer.0 as usize].score += 1;
+        }
+    }
+
+    fn observation<'py>(
+        &self,
+        py: Python<'py>,
+        tick: &TickMessage,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let obs = PyDict::new(py);
+        obs.set_item("tick", tick.tick)?;
+        obs.set_item("player", self.player_observation(py, tick, self.player_id)?)?;
+        obs.set_item(
+            "opponent",
+            self.player_observation(py, tick, self.player_id ^ 1)?,
+        )?;
+
+        let block_updates = PyList::empty(py);
+        for (pos, block) in tick.block_updates.iter() {
+            block_updates.append((pos.to_array(), format!("{:?}", block)))?;
+        }
+        obs.set_item("block_updates", block_updates)?;
+
+        let hits = PyList::empty(py);
+        for hit in tick.hits.iter() {
+            let entry = PyDict::new(py);
+            entry.set_item("attacker", hit.attacker.0)?;
+            entry.set_item("target", hit.target.0)?;
+            entry.set_item("damage", hit.damage)?;
+            entry.set_item("knockback", hit.knockback.to_array())?;
+            entry.set_item("critical", hit.critical)?;
+            hits.append(entry)?;
+        }
+        obs.set_item("hits", hits)?;
+
+        let damage = PyList::empty(py);
+        for event in tick.damage.iter() {
+            let (source, attacker) = match event.source {
+                DamageSource::Melee(attacker) => ("Melee", Some(attacker.0)),
+                DamageSource::Arrow(shooter) => ("Arrow", Some(shooter.0)),
+                DamageSource::Fall => ("Fall", None),
+                DamageSource::Void => ("Void", None),
+            };
+            let entry = PyDict::new(py);
+            entry.set_item("player_id", event.player_id.0)?;
+            entry.set_item("amount", event.amount)?;
+            entry.set_item("source", source)?;
+            entry.set_item("attacker", attacker)?;
+            damage.append(entry)?;
+        }
+        obs.set_item("damage", damage)?;
+        obs.set_item("ticks_remaining", tick.ticks_remaining)?;
+        obs.set_item("sudden_death", tick.sudden_death)?;
+        Ok(obs)
+    }
+
+    fn player_observation<'py>(
+        &self,
+        py: Python<'py>,
+        tick: &TickMessage,
+        player_id: usize,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let info = &tick.players[player_id];
+        let state = &self.players[player_id];
+
+        let inventory = PyDict::new(py);
+        for (item, count) in state.inventory.contents() {
+            inventory.set_item(item.to_string(), count)?;
+        }
+
+        let obs = PyDict::new(py);
+        obs.set_item("position", info.position.to_array())?;
+        obs.set_item("velocity", info.velocity.to_array())?;
+        obs.set_item("yaw", info.yaw)?;
+        obs.set_item("pitch", info.pitch)?;
+        obs.set_item("stance", format!("{:?}", info.stance))?;
+        obs.set_item("attack_charge", info.attack_charge)?;
+        obs.set_item("bow_charge", info.bow_charge)?;
+        obs.set_item("health", state.health)?;
+        obs.set_item("score", state.score)?;
+        obs.set_item(
+            "selected_item",
+            state.inventory.get_selected_item().to_string(),
+        )?;
+        obs.set_item("inventory", inventory)?;
+        Ok(obs)
+    }
+
+    fn info<'py>(&self, py: Python<'py>, tick: &TickMessage) -> PyResult<Bound<'py, PyDict>> {
+        let info = PyDict::new(py);
+        info.set_item("tick", tick.tick)?;
+        if let Some(sim) = &self.sim {
+            info.set_item("seed", sim.seed())?;
+        }
+        if let Some(results) = &tick.game_results {
+            info.set_item("winner", results.winner)?;
+            info.set_item("reason", results.reason.as_str())?;
+        }
+        Ok(info)
+    }
+}
+
+fn parse_action(action: Option<Bound<'_, PyAny>>) -> PyResult<PlayerActions> {
+    let mut actions = PlayerActions::default();
+    let Some(action) = action else {
+        return Ok(actions);
+    };
+    let action = action.extract::<Bound<'_, PyDict>>()?;
+
+    for (key, flag) in ACTION_FLAGS {
+        if let Some(value) = action.get_item(key)?
+            && value.is_truthy()?
+        {
+            // Like on the control socket, only the first hand action (in the order above) counts
+            actions.checked_set(flag);
+        }
+    }
+
+    let mut rotation = Rotation::default();
+    if let Some(yaw) = action.get_item("yaw")? {
+        rotation.yaw = yaw.extract()?;
+    }
+    if let Some(pitch) = action.get_item("pitch")? {
+        rotation.pitch = pitch.extract()?;
+    }
+    actions.rotation = rotation;
+
+    if let Some(item) = action.get_item("select_item")? {
+        let name = item.extract::<String>()?;
+        let item = serde_json::from_value::<Item>(serde_json::Value::String(name.clone()))
+            .map_err(|_| PyValueError::new_err(format!("Unknown item: {}", name)))?;
+        actions.item_change = Some(item);
+    }
+
+    Ok(actions)
+}
+
+#[pymodule]
+fn agentduels_gym(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<DuelEnv>()?;
+    Ok(())
+}
@@ -7,6 +7,8 @@ class EventManager:
     def __init__(self):
         self.on_tick = []
         self.on_health_change = []
+        self.on_hit = []
+        self.on_damage = []
         self.on_death = []
         self.on_goal = []
         self.on_block_change = []
@@ -22,6 +24,12 @@ class EventManager:
     def health_change(self, player_id, old_health, new_health):
         EventManager.run_event(self.on_health_change, (player_id, old_health, new_health,))
 
+    def hit(self, attacker, target, damage, knockback, critical):
+        EventManager.run_event(self.on_hit, (attacker, target, damage, knockback, critical,))
+
+    def damage(self, player_id, amount, source):
+        EventManager.run_event(self.on_damage, (player_id, amount, source,))
+
     def death(self, player_id):
         EventManager.run_event(self.on_death, (player_id,))
 
@@ -80,6 +88,8 @@ class Actions:
         self.used_item = False
         self.placed_block = False
         self.dug_block = False
+        self.sprinted = False
+        self.sneaked = False
         self.rotated = (0.0, 0.0)
         self.item_changed = None
 
@@ -94,6 +104,8 @@ class Actions:
         self.used_item = bool(bits & (1 << 6))
         self.placed_block = bool(bits & (1 << 7))
         self.dug_block = bool(bits & (1 << 8))
+        self.sprinted = bool(bits & (1 << 9))
+        self.sneaked = bool(bits & (1 << 10))
 
         rot = actions_data["rotation"]
         self.rotated = Rotation(rot["yaw"], rot["pitch"])
@@ -105,6 +117,14 @@ class Player:
         self.pos = Position()
         self.head_rot = Rotation()
         self.health = float(20)
+        # How far the bow is drawn, from 0 to 1. Arrows are shot when use_item stops being sent
+        self.bow_charge = 0.0
+        # The block being broken and how far, as ([x, y, z], progress from 0 to 1), or None
+        self.breaking = None
+        # "Standing", "Sprinting" or "Sneaking"
+        self.stance = "Standing"
+        # How strong an attack with the held item would be, from 0 to 1. Attacking resets it
+        self.attack_charge = 1.0
         self.inventory = Inventory()
         self.actions = None
 
@@ -146,6 +166,20 @@ class ChunkMap:
             self.chunks[chunk_key] = Chunk()
         self.chunks[chunk_key].blocks[local_x][local_y][local_z] = block_type
 
+    def load(self, map_data):
+        """Replaces the map with the one from a snapshot"""
+        self.chunks = {}
+        for serialized in map_data["chunks"]:
+            chunk = Chunk()
+            palette = serialized["palette"]
+            i = 0
+            for (index, count) in serialized["runs"]:
+                for _ in range(count):
+                    # Snapshots index the blocks as [x][z][y]
+                    chunk.blocks[i // 256][i % 16][(i // 16) % 16] = palette[index]
+                    i += 1
+            self.chunks[tuple(serialized["pos"])] = chunk
+
 class GameState:
     def __init__(self):
         self.player_id = None
@@ -154,10 +188,15 @@ class GameState:
             1: Player()
         }
         self.map = ChunkMap()
+        # The arena from the Map event: name, fills, spawns, goals, kill_plane and build_limits
+        self.map_def = None
         self.scores = {
             0: 0,
             1: 0
         }
+        # Ticks left until the time limit, None if there is no limit or the match is in sudden death
+        self.ticks_remaining = None
+        self.sudden_death = False
 
 class AgentDuelsClient:
     def __init__(self):
@@ -165,8 +204,8 @@ class AgentDuelsClient:
         self.events = EventManager()
 
     def send_message(self, message_type, value):
-        msg = json.dumps({message_type: value}).encode()
-        self.socket.send(msg)
+        msg = json.dumps({message_type: value}) + "\n"
+        self.socket.sendall(msg.encode())
 
     def move_forward(self):
         self.send_message("MoveForward", None)
@@ -183,6 +222,14 @@ class AgentDuelsClient:
     def jump(self):
         self.send_message("Jump", None)
 
+    def sprint(self):
+        """Move faster and knock back harder, only while moving forward."""
+        self.send_message("Sprint", None)
+
+    def sneak(self):
+        """Move slower with a lower hitbox, without walking off block edges. Takes precedence over sprinting."""
+        self.send_message("Sneak", None)
+
     def rotate(self, yaw: float, pitch: float):
         """
         Rotate the player's head.
@@ -213,44 +260,94 @@ class AgentDuelsClient:
         self.socket = socket(AF_INET)
         self.socket.connect(("127.0.0.1", port))
         if verbosity > 0: print(f"[*] Connected to the server at port {port}!")
+        # Messages are JSON, one per line
+        reader = self.socket.makefile("rb")
+        tick = None
         while True:
-            response = self.socket.recv(2048)
+            response = reader.readline()
             if response == b"":
                 if verbosity > 0: print("[*] Server closed the connection.")
                 break
             try:
-                (player_id, msg) = json.loads(response.decode())
-            except json.JSONDecodeError:
+                event = json.loads(response.decode())
+                ((kind, value),) = event.items()
+            except (json.JSONDecodeError, AttributeError, ValueError):
                 if verbosity > 0: print("[!] Failed to decode message from server. Message was:", response)
                 break
-            if self.state.player_id is None:
-                self.state.player_id = player_id
-                if verbosity > 0: print(f"[*] Assigned player ID: {self.state.player_id}")
-            for (player_id, player) in self.state.players.items():
-                player_info = msg["players"][player_id]
-                player.pos = Position(*player_info["position"])
-                player.head_rot = Rotation(player_info["yaw"], player_info["pitch"])
-                if player_info["health_update"] is not None:
-                    old_health = player.health
-                    player.health = player_info["health_update"]
-                    self.events.health_change(player_id, old_health, player.health)
-                if player_info["inventory_update"] is not None:
-                    player.inventory.update(player_info["inventory_update"])
-                    self.events.inventory_change(player_id)
-            for player_id in msg["deaths"]:
-                self.events.death(player_id)
-            if msg["goals"] is not None:
-                player_id = msg["goals"]
-                self.state.scores[player_id] += 1
-                self.events.goal(player_id)
-            for (block_pos, block_type) in msg["block_updates"]:
+            if kind == "Map":
+                self.state.map_def = value
+            elif kind == "Snapshot":
+                for player_id in (0, 1):
+                    player = self.state.players[player_id]
+                    info = value["players"][player_id]
+                    player.pos = Position(*info["position"])
+                    player.head_rot = Rotation(info["yaw"], info["pitch"])
+                    player.bow_charge = info["bow_charge"]
+                    player.stance = info["stance"]
+                    player.attack_charge = info["attack_charge"]
+                    breaking = info["breaking"]
+                    player.breaking = (breaking["block_pos"], breaking["progress"]) if breaking else None
+                    player.health = value["health"][player_id]
+                    player.inventory = Inventory()
+                    player.inventory.update(value["inventories"][player_id])
+                    self.state.scores[player_id] = value["scores"][player_id]
+                self.state.map.load(value["map"])
+            elif kind == "TickStart":
+                if self.state.player_id is None:
+                    self.state.player_id = value["player_id"]
+                    if verbosity > 0: print(f"[*] Assigned player ID: {self.state.player_id}")
+                player = self.state.players[self.state.player_id]
+                opponent = self.state.players[self.state.player_id ^ 1]
+                player.pos = Position(*value["player_position"])
+                opponent.pos = Position(*value["opponent_position"])
+                player.head_rot = Rotation(value["player_rotation"]["yaw"], value["player_rotation"]["pitch"])
+                opponent.head_rot = Rotation(value["opponent_rotation"]["yaw"], value["opponent_rotation"]["pitch"])
+                player.bow_charge = value["player_bow_charge"]
+                opponent.bow_charge = value["opponent_bow_charge"]
+                player.stance = value["player_stance"]
+                opponent.stance = value["opponent_stance"]
+                player.attack_charge = value["player_attack_charge"]
+                opponent.attack_charge = value["opponent_attack_charge"]
+                # Only players that are still breaking a block get a BlockBreaking event
+                player.breaking = None
+                opponent.breaking = None
+                opponent.actions = Actions()
+                opponent.actions.update(value["opponent_prev_actions"])
+                self.state.ticks_remaining = value["ticks_remaining"]
+                self.state.sudden_death = value["sudden_death"]
+                tick = value["tick"]
+            elif kind == "Hit":
+                self.events.hit(value["attacker"], value["target"], value["damage"], value["knockback"], value["critical"])
+            elif kind == "Damage":
+                # source is {"Melee": attacker_id}, {"Arrow": shooter_id}, "Fall" or "Void"
+                self.events.damage(value["player_id"], value["amount"], value["source"])
+            elif kind == "HealthUpdate":
+                player = self.state.players[value["player_id"]]
+                old_health = player.health
+                player.health = value["new_health"]
+                self.events.health_change(value["player_id"], old_health, player.health)
+            elif kind == "Death":
+                self.events.death(value["player_id"])
+            elif kind == "Goal":
+                self.state.scores[value["player_id"]] += 1
+                self.events.goal(value["player_id"])
+            elif kind == "BlockUpdate":
+                (block_pos, block_type) = value
                 self.state.map.set_block(block_pos[0], block_pos[1], block_pos[2], block_type)
                 self.events.block_change(block_pos, block_type)
-            self.events.tick(msg["tick"])
-            self.send_message("EndTick", None)
-            # TODO - arrow update handling
-            if msg["game_results"] is not None:
+            elif kind == "BlockBreaking":
+                self.state.players[value["player_id"]].breaking = (value["block_pos"], value["progress"])
+            elif kind == "InventoryUpdate":
+                self.state.players[value["player_id"]].inventory.update(value["new_contents"])
+                self.events.inventory_change(value["player_id"])
+            elif kind == "TickEnd":
+                self.events.tick(tick)
+                self.send_message("EndTick", None)
+            elif kind == "GameOver":
                 if verbosity > 0:
-                    print(f"[*] Game over! Results: {msg['game_results']}")
+                    print(f"[*] Game over! Results: {value}")
                 break
+            elif kind == "Error":
+                if verbosity > 0: print("[!] The client rejected a message:", value)
+        reader.close()
         self.socket.close()
\ No newline at end of file